@@ -0,0 +1,575 @@
+//! Bidirectional path tracing, after Veach's thesis and pbrt. Each sample
+//! traces a subpath from the camera and another from one of the lights with
+//! a position, and joins every vertex of the one to every vertex of the
+//! other. Every path can be made in several of these ways, which are
+//! weighted against each other with the power heuristic. Joining a light
+//! subpath straight to the camera lights up whichever pixel the vertex is
+//! seen through, which goes back to the film as a splat.
+//!
+//! Distant lights and the environment can't be traced from, and get
+//! sampled from the camera subpath the same way the path tracer does.
+//! Emissive surfaces only light the scene where the camera subpath hits
+//! them, as in the path tracer, which is the one way of making those paths.
+//! Subpaths end by Russian roulette like the path tracer's, or after
+//! settings.depth bounces at the latest.
+//! Participating media are left out, and their boundaries are seen
+//! through.
+
+use crate::camera::Camera;
+use crate::ray::{roulette, sample_environment, FirstHit, PathSample, Ray};
+use crate::sampler::Sampler;
+use crate::settings::RenderSettings;
+use crate::stats::RenderStats;
+use crate::typedefs::{Intersection, Material, Scene};
+use crate::vect::*;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Camera,
+    /// A light with a position, by its index in the scene
+    Light(usize),
+    /// Surface of the object with the given index
+    Surface(Material, usize),
+}
+
+#[derive(Clone, Copy)]
+struct Vertex {
+    kind: Kind,
+    pos: Vect,
+    /// Zero for the camera and lights, which aren't on a surface
+    normal: Vect,
+    /// The normal tilted by the surface map, which scattering goes by
+    shading_normal: Vect,
+    /// Light (on light subpaths) or importance (on camera subpaths) carried
+    /// to the vertex, divided by the density of the subpath so far
+    beta: Vect,
+    /// Density with respect to area of sampling the vertex from the one
+    /// before it on its subpath
+    pdf_fwd: f64,
+    /// Same, from the one after it, as if the subpath went the other way
+    pdf_rev: f64,
+    /// Whether the vertex scattered specularly, so that nothing can be
+    /// joined to it
+    delta: bool,
+}
+
+impl Vertex {
+    fn endpoint(kind: Kind, pos: Vect, beta: Vect, pdf_fwd: f64) -> Vertex {
+        Vertex {
+            kind,
+            pos,
+            normal: zero(),
+            shading_normal: zero(),
+            beta,
+            pdf_fwd,
+            pdf_rev: 0f64,
+            delta: false,
+        }
+    }
+
+    /// Light arriving from from, scattered by the vertex towards to. Not
+    /// every material scatters light the same both ways.
+    fn f(&self, from: &Vect, to: &Vect) -> Vect {
+        match self.kind {
+            Kind::Surface(material, _) => material.eval(
+                &self.shading_normal,
+                &to.sub(&self.pos).normalise(),
+                &from.sub(&self.pos).normalise(),
+            ),
+            _ => zero(),
+        }
+    }
+
+    /// Cosine between the geometric normal and the direction to pos, one off
+    /// surfaces
+    fn cos(&self, pos: &Vect) -> f64 {
+        if self.normal == zero() {
+            return 1f64;
+        }
+        self.normal.dot(&pos.sub(&self.pos).normalise()).abs()
+    }
+
+    /// Density with respect to area of sampling next from the vertex,
+    /// having arrived from prev
+    fn pdf(&self, cam: &Camera, scene: &Scene, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let dir = next.pos.sub(&self.pos).normalise();
+        let pdf_dir = match (self.kind, prev) {
+            (Kind::Camera, _) => cam.pdf(&dir),
+            (Kind::Light(i), _) => scene.1[i].emit_pdf(&dir),
+            (Kind::Surface(material, _), Some(prev)) => material.pdf(
+                &self.shading_normal,
+                &prev.pos.sub(&self.pos).normalise(),
+                &dir,
+            ),
+            (Kind::Surface(..), None) => 0f64,
+        };
+        to_area(pdf_dir, &self.pos, next)
+    }
+
+    fn intersection(&self) -> Intersection {
+        Intersection {
+            pos: self.pos,
+            normal: self.normal,
+            shading_normal: self.shading_normal,
+            uv: (0f64, 0f64),
+            uv_scale: (0f64, 0f64),
+            tangent: zero(),
+        }
+    }
+}
+
+/// Density with respect to solid angle at from, turned into density with
+/// respect to area at to
+fn to_area(pdf_dir: f64, from: &Vect, to: &Vertex) -> f64 {
+    pdf_dir * to.cos(from) / to.pos.sub(from).norm_sq()
+}
+
+/// Follow ray through the scene for up to settings.depth surfaces, adding a
+/// vertex to path for each, until Russian roulette ends it. The ray left
+/// the last vertex of path with density pdf_dir, carrying beta. Returns the
+/// direction and beta of the ray that left the scene, if one did.
+#[allow(clippy::too_many_arguments)]
+fn random_walk(
+    mut ray: Ray,
+    mut beta: Vect,
+    pdf_dir: f64,
+    settings: &RenderSettings,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+    path: &mut Vec<Vertex>,
+) -> Option<(Vect, Vect)> {
+    let mut pdf_fwd = pdf_dir;
+    // Light subpaths carry light the other way to the one eval() takes
+    let adjoint = matches!(path[0].kind, Kind::Light(_));
+    // What's left of beta relative to where the subpath started, for
+    // Russian roulette
+    let mut throughput = Vect(1.0, 1.0, 1.0);
+    for bounce in 0..settings.depth {
+        stats.rays += 1;
+        let (intersection, material, object) = match ray.closest_hit(scene) {
+            Some(hit) => hit,
+            None => return Some((ray.1, beta)),
+        };
+        let normal = intersection.shading_normal;
+        let prev = path.len() - 1;
+        let mut vertex = Vertex {
+            kind: Kind::Surface(material, object),
+            pos: intersection.pos,
+            normal: intersection.normal,
+            shading_normal: normal,
+            beta,
+            pdf_fwd: 0f64,
+            pdf_rev: 0f64,
+            delta: false,
+        };
+        vertex.pdf_fwd = to_area(pdf_fwd, &path[prev].pos, &vertex);
+        path.push(vertex);
+        let wo = ray.1.scalar_mul(&-1f64);
+        let (u1, u2) = sampler.get_2d();
+        let sample = match material.sample(&normal, &wo, u1, u2) {
+            Some(sample) => sample,
+            None => break,
+        };
+        let pdf_rev = if sample.specular {
+            path[prev + 1].delta = true;
+            pdf_fwd = 0f64;
+            0f64
+        } else {
+            pdf_fwd = sample.pdf;
+            material.pdf(&normal, &sample.wi, &wo)
+        };
+        path[prev].pdf_rev = to_area(pdf_rev, &intersection.pos, &path[prev]);
+        let weight = if adjoint && !sample.specular {
+            material
+                .eval(&normal, &sample.wi, &wo)
+                .scalar_mul(&(sample.wi.dot(&normal).abs() / sample.pdf))
+        } else {
+            sample.weight(&normal)
+        };
+        beta = beta.pointwise_mul(&weight);
+        throughput = throughput.pointwise_mul(&weight);
+        ray = Ray::leaving(&intersection, sample.wi);
+        match roulette(&throughput, bounce, settings, sampler) {
+            Some(scale) => {
+                beta = beta.scalar_mul(&scale);
+                throughput = throughput.scalar_mul(&scale);
+            }
+            None => break,
+        }
+    }
+    None
+}
+
+/// Light arriving at the camera along ray, estimated by bidirectional path
+/// tracing. The light subpath's contributions to other pixels come back as
+/// splats.
+pub fn trace(
+    ray: &Ray,
+    cam: &Camera,
+    scene: &Scene,
+    settings: &RenderSettings,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> PathSample {
+    stats.camera_rays += 1;
+    let mut camera_path = vec![Vertex::endpoint(
+        Kind::Camera,
+        ray.0,
+        Vect(1.0, 1.0, 1.0),
+        0f64,
+    )];
+    let escaped = random_walk(
+        *ray,
+        Vect(1.0, 1.0, 1.0),
+        cam.pdf(&ray.1),
+        settings,
+        scene,
+        sampler,
+        stats,
+        &mut camera_path,
+    );
+    let light_path = light_subpath(scene, settings, sampler, stats);
+    let local_lights = scene.1.iter().filter(|l| l.position().is_some()).count();
+
+    let mut colour = zero();
+    let mut direct = zero();
+    // Everything up to the first vertex that isn't specular counts as
+    // direct light, unless it's been scattered on the light subpath
+    let first_diffuse = camera_path
+        .iter()
+        .skip(1)
+        .position(|v| !v.delta)
+        .map_or(camera_path.len(), |i| i + 1);
+    let last = camera_path[camera_path.len() - 1];
+    if let Some((dir, beta)) = escaped {
+        if matches!(last.kind, Kind::Camera) || last.delta {
+            colour = beta.pointwise_mul(&scene.2.radiance(&dir));
+            direct = colour;
+        }
+    }
+    for t in 2..camera_path.len() + 1 {
+        let pt = camera_path[t - 1];
+        // The camera subpath hitting the light, with no other way of
+        // sampling the path to weigh it against
+        if let Kind::Surface(material, _) = pt.kind {
+            let emitted = pt.beta.pointwise_mul(&material.emission());
+            colour = colour.add(&emitted);
+            if t - 1 <= first_diffuse {
+                direct = direct.add(&emitted);
+            }
+        }
+        if pt.delta {
+            continue;
+        }
+        let prev = camera_path[t - 2].pos;
+        let intersection = pt.intersection();
+        // One light vertex, sampled on its own
+        let mut res = zero();
+        for (i, light) in scene.1.iter().enumerate() {
+            let (u1, u2) = sampler.get_2d();
+            let sample =
+                match light.get_contribution(&intersection, scene, None, sampler, stats, u1, u2) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+            let light_pos = pt.pos.add(&sample.dir);
+            let l = pt
+                .beta
+                .pointwise_mul(&pt.f(&light_pos, &prev))
+                .scalar_mul(&sample.strength);
+            res = res.add(&match light.position() {
+                None => l,
+                Some(pos) => {
+                    let sampled = Vertex::endpoint(Kind::Light(i), pos, zero(), 1f64);
+                    let weight =
+                        mis_weight(cam, scene, (&[sampled], &camera_path[..t]), local_lights);
+                    l.scalar_mul(&weight)
+                }
+            });
+        }
+        if let Some((dir, env_light)) =
+            sample_environment(&intersection, None, scene, sampler, stats)
+        {
+            let towards = pt.pos.add(&dir);
+            res = res.add(
+                &pt.beta
+                    .pointwise_mul(&pt.f(&towards, &prev))
+                    .pointwise_mul(&env_light),
+            );
+        }
+        colour = colour.add(&res);
+        if t - 1 <= first_diffuse {
+            direct = direct.add(&res);
+        }
+        // Vertices of the light subpath past the light itself
+        for s in 2..light_path.len() + 1 {
+            if s + t > settings.depth as usize + 2 {
+                break;
+            }
+            let qs = light_path[s - 1];
+            if qs.delta {
+                continue;
+            }
+            let d = pt.pos.sub(&qs.pos);
+            let dist_sq = d.norm_sq();
+            let l = qs
+                .beta
+                .pointwise_mul(&qs.f(&light_path[s - 2].pos, &pt.pos))
+                .pointwise_mul(&pt.f(&qs.pos, &prev))
+                .pointwise_mul(&pt.beta)
+                .scalar_mul(&(qs.cos(&pt.pos) * pt.cos(&qs.pos) / dist_sq));
+            if l == zero() {
+                continue;
+            }
+            let dist = dist_sq.sqrt();
+            if Ray::leaving(&qs.intersection(), d.scalar_mul(&(1f64 / dist))).occluded(
+                scene,
+                dist - crate::EPSILON,
+                stats,
+            ) {
+                continue;
+            }
+            let weight = mis_weight(
+                cam,
+                scene,
+                (&light_path[..s], &camera_path[..t]),
+                local_lights,
+            );
+            colour = colour.add(&l.scalar_mul(&weight));
+        }
+    }
+    PathSample {
+        colour,
+        direct,
+        first_hit: camera_path.get(1).map(|v| match v.kind {
+            Kind::Surface(material, object) => FirstHit {
+                pos: v.pos,
+                normal: v.shading_normal,
+                albedo: material.albedo(),
+                object,
+            },
+            _ => unreachable!(),
+        }),
+        splats: light_splats(
+            cam,
+            scene,
+            settings.depth,
+            (&light_path, &camera_path[..1]),
+            local_lights,
+            stats,
+        ),
+    }
+}
+
+/// A subpath from one of the lights with a position, picked uniformly, or
+/// nothing if there are none
+fn light_subpath(
+    scene: &Scene,
+    settings: &RenderSettings,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> Vec<Vertex> {
+    let mut path = Vec::new();
+    let local: Vec<usize> = (0..scene.1.len())
+        .filter(|i| scene.1[*i].position().is_some())
+        .collect();
+    let u = sampler.get_1d();
+    let (u1, u2) = sampler.get_2d();
+    if local.is_empty() {
+        return path;
+    }
+    let i = local[((u * local.len() as f64) as usize).min(local.len() - 1)];
+    let light = &scene.1[i];
+    let (pos, emission) = match (light.position(), light.emit(u1, u2)) {
+        (Some(pos), Some(emission)) if emission.pdf > 0f64 && emission.intensity > 0f64 => {
+            (pos, emission)
+        }
+        _ => return path,
+    };
+    let selection = 1f64 / local.len() as f64;
+    let intensity = Vect(1.0, 1.0, 1.0).scalar_mul(&(emission.intensity / selection));
+    path.push(Vertex::endpoint(Kind::Light(i), pos, intensity, selection));
+    random_walk(
+        Ray(pos, emission.dir),
+        intensity.scalar_mul(&(1f64 / emission.pdf)),
+        emission.pdf,
+        settings,
+        scene,
+        sampler,
+        stats,
+        &mut path,
+    );
+    path
+}
+
+/// Light from each vertex of the light subpath past the light that reaches
+/// the camera, and the pixels it lands on
+fn light_splats(
+    cam: &Camera,
+    scene: &Scene,
+    depth: u8,
+    (light_path, camera): (&[Vertex], &[Vertex]),
+    local_lights: usize,
+    stats: &mut RenderStats,
+) -> Vec<((u32, u32), Vect)> {
+    let mut splats = Vec::new();
+    for s in 2..(light_path.len() + 1).min(depth as usize + 2) {
+        let qs = light_path[s - 1];
+        if qs.delta {
+            continue;
+        }
+        let (row, col) = match cam.raster(&qs.pos) {
+            Some(pixel) => pixel,
+            None => continue,
+        };
+        let d = qs.pos.sub(&cam.pos());
+        let dist_sq = d.norm_sq();
+        let dir = d.scalar_mul(&(1f64 / dist_sq.sqrt()));
+        // For a pinhole camera the importance times the cosine at the
+        // camera is the density of camera rays in that direction
+        let l = qs
+            .beta
+            .pointwise_mul(&qs.f(&light_path[s - 2].pos, &cam.pos()))
+            .scalar_mul(&(qs.cos(&cam.pos()) * cam.pdf(&dir) / dist_sq));
+        if l == zero() {
+            continue;
+        }
+        let towards_camera = dir.scalar_mul(&-1f64);
+        if Ray::leaving(&qs.intersection(), towards_camera).occluded(scene, dist_sq.sqrt(), stats) {
+            continue;
+        }
+        let weight = mis_weight(cam, scene, (&light_path[..s], camera), local_lights);
+        splats.push(((row, col), l.scalar_mul(&weight)));
+    }
+    splats
+}
+
+/// Power heuristic weight of the path made of the given light and camera
+/// subpaths, joined at their last vertices, against every other way of
+/// sampling it. A light subpath of one vertex is a light sampled from the
+/// camera subpath, which tries every light rather than picking one out of
+/// local_lights.
+fn mis_weight(
+    cam: &Camera,
+    scene: &Scene,
+    (light_path, camera_path): (&[Vertex], &[Vertex]),
+    local_lights: usize,
+) -> f64 {
+    let (s, t) = (light_path.len(), camera_path.len());
+    let mut light = light_path.to_vec();
+    let mut camera = camera_path.to_vec();
+    // The densities the vertices either side of the join would have been
+    // sampled with going the other way
+    let (qs, pt) = (s - 1, t - 1);
+    camera[pt].pdf_rev = light[qs].pdf(
+        cam,
+        scene,
+        qs.checked_sub(1).map(|i| &light[i]),
+        &camera[pt],
+    );
+    if t > 1 {
+        camera[pt - 1].pdf_rev = camera[pt].pdf(cam, scene, Some(&light[qs]), &camera[pt - 1]);
+    }
+    light[qs].pdf_rev = camera[pt].pdf(
+        cam,
+        scene,
+        pt.checked_sub(1).map(|i| &camera[i]),
+        &light[qs],
+    );
+    if s > 1 {
+        light[qs - 1].pdf_rev = light[qs].pdf(cam, scene, Some(&camera[pt]), &light[qs - 1]);
+    }
+    // Specular vertices have no density, which cancels out
+    let remap = |pdf: f64| if pdf == 0f64 { 1f64 } else { pdf };
+    let n = local_lights as f64;
+    let mut sum = 0f64;
+    // Ratios of the density of each other way to this one's, first moving
+    // the join towards the camera
+    let mut ratio = 1f64;
+    for i in (1..t).rev() {
+        ratio *= remap(camera[i].pdf_rev) / remap(camera[i].pdf_fwd);
+        if s == 1 && i == pt {
+            // Light subpaths pick one of the lights
+            ratio /= n;
+        }
+        if !camera[i].delta && !camera[i - 1].delta {
+            sum += ratio * ratio;
+        }
+    }
+    ratio = 1f64;
+    for i in (0..s).rev() {
+        ratio *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+        if i == 1 {
+            ratio *= n;
+        }
+        // The lights are all points, which can't be hit
+        if i > 0 && !light[i].delta && !light[i - 1].delta {
+            sum += ratio * ratio;
+        }
+    }
+    1f64 / (1f64 + sum)
+}
+
+#[test]
+fn mis_weight_test() {
+    use crate::environment::Constant;
+    use crate::light::Pointlight;
+    use crate::plane::Plane;
+    // Light from a point light bouncing off a wall and the floor into the
+    // camera can be made three ways, whose weights add up to one
+    let lambertian = Material::Lambertian(Vect(0.5, 0.5, 0.5));
+    let scene: Scene = (
+        vec![
+            Box::new(Plane {
+                point: zero(),
+                normal: Vect(0.0, 1.0, 0.0),
+                material: lambertian,
+                surface_map: None,
+            }),
+            Box::new(Plane {
+                point: Vect(0.0, 0.0, 10.0),
+                normal: Vect(0.0, 0.0, -1.0),
+                material: lambertian,
+                surface_map: None,
+            }),
+        ],
+        vec![Box::new(Pointlight {
+            pos: Vect(1.0, 4.0, 8.0),
+            intensity: 1f64,
+        })],
+        Box::new(Constant(zero())),
+        None,
+    );
+    let cam = crate::camera::new(
+        Vect(0.0, 2.0, 0.0),
+        Vect(0.0, 0.0, 1.0),
+        Vect(0.0, 1.0, 0.0),
+        std::f64::consts::PI / 3f64,
+    );
+    let surface = |object: usize, pos, normal| Vertex {
+        normal,
+        shading_normal: normal,
+        ..Vertex::endpoint(Kind::Surface(lambertian, object), pos, zero(), 0f64)
+    };
+    let mut path = [
+        Vertex::endpoint(Kind::Light(0), Vect(1.0, 4.0, 8.0), zero(), 1f64),
+        surface(1, Vect(0.0, 2.0, 10.0), Vect(0.0, 0.0, -1.0)),
+        surface(0, Vect(0.0, 0.0, 5.0), Vect(0.0, 1.0, 0.0)),
+        Vertex::endpoint(Kind::Camera, cam.pos(), zero(), 0f64),
+    ];
+    // Forwards from the light, and backwards from the camera
+    path[1].pdf_fwd = path[0].pdf(&cam, &scene, None, &path[1]);
+    path[2].pdf_fwd = path[1].pdf(&cam, &scene, Some(&path[0]), &path[2]);
+    path[2].pdf_rev = path[3].pdf(&cam, &scene, None, &path[2]);
+    path[1].pdf_rev = path[2].pdf(&cam, &scene, Some(&path[3]), &path[1]);
+    path[0].pdf_rev = path[1].pdf(&cam, &scene, Some(&path[2]), &path[0]);
+    let mut camera_path: Vec<Vertex> = path.iter().rev().copied().collect();
+    for v in camera_path.iter_mut() {
+        (v.pdf_fwd, v.pdf_rev) = (v.pdf_rev, v.pdf_fwd);
+    }
+    let total: f64 = (1..4)
+        .map(|s| mis_weight(&cam, &scene, (&path[..s], &camera_path[..4 - s]), 1))
+        .sum();
+    assert!((total - 1f64).abs() < 1e-12);
+}
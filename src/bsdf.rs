@@ -0,0 +1,365 @@
+//! How each material scatters light. Directions all point away from the
+//! surface: wo towards where the light goes (the viewer), wi towards where
+//! it comes from.
+
+use crate::sampling::*;
+use crate::typedefs::Material;
+use crate::vect::*;
+use std::f64::consts::PI;
+
+/// Shininess from which Phong highlights are taken to be perfect
+/// reflections. Any sharper and only sampling the material would ever find
+/// them, leaving fireflies wherever a light or the sky shows up in them.
+pub const MIRROR_SHININESS: f64 = 2e4;
+
+pub struct BsdfSample {
+    pub wi: Vect,
+    /// BSDF value for the sampled direction
+    pub f: Vect,
+    /// Density of wi with respect to solid angle. Meaningless for specular
+    /// samples, whose f is already divided by it.
+    pub pdf: f64,
+    /// Whether the material only ever scatters into this one direction
+    pub specular: bool,
+}
+
+impl BsdfSample {
+    /// What the path throughput gets multiplied by when following the sample
+    pub fn weight(&self, normal: &Vect) -> Vect {
+        self.f.scalar_mul(&(self.wi.dot(normal).abs() / self.pdf))
+    }
+}
+
+impl Material {
+    /// Whether the material only reflects light in perfect mirror-like
+    /// directions, in which case eval() and pdf() are always zero.
+    pub fn is_specular(&self) -> bool {
+        match self {
+            Material::Lambertian(_)
+            | Material::Phong(..)
+            | Material::Subsurface(..)
+            | Material::Principled(_) => false,
+            Material::Mirror | Material::Glass(_) | Material::Interface => true,
+        }
+    }
+
+    /// Colour of the material, white for mirrors, glass and interfaces
+    pub fn albedo(&self) -> Vect {
+        match self {
+            Material::Lambertian(albedo)
+            | Material::Phong(albedo, _, _)
+            | Material::Subsurface(albedo, _) => *albedo,
+            Material::Principled(principled) => principled.albedo(),
+            Material::Mirror | Material::Glass(_) | Material::Interface => Vect(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// Radiance the surface gives off by itself
+    pub fn emission(&self) -> Vect {
+        match self {
+            Material::Principled(principled) => principled.emission,
+            _ => zero(),
+        }
+    }
+
+    /// Fraction of light arriving from wi that leaves towards wo, per
+    /// steradian. Zero for specular materials.
+    pub fn eval(&self, normal: &Vect, wo: &Vect, wi: &Vect) -> Vect {
+        match self {
+            Material::Lambertian(albedo) | Material::Subsurface(albedo, _) => {
+                if normal.dot(wo) <= 0f64 || normal.dot(wi) <= 0f64 {
+                    return zero();
+                }
+                albedo.scalar_mul(&(1f64 / PI))
+            }
+            Material::Phong(colour, specular, shininess) => {
+                if normal.dot(wo) <= 0f64 || normal.dot(wi) <= 0f64 {
+                    return zero();
+                }
+                // Normalised so that the highlight reflects about specular
+                // of the light whatever the shininess
+                if *shininess >= MIRROR_SHININESS {
+                    return colour.scalar_mul(&(1f64 / PI));
+                }
+                let cos_h = normal.dot(&wo.add(wi).normalise()).max(0f64);
+                let highlight =
+                    specular * (shininess + 8f64) / (8f64 * PI) * cos_h.powf(*shininess);
+                colour
+                    .scalar_mul(&(1f64 / PI))
+                    .add(&Vect(highlight, highlight, highlight))
+            }
+            Material::Principled(principled) => principled.eval(normal, wo, wi),
+            Material::Mirror | Material::Glass(_) | Material::Interface => zero(),
+        }
+    }
+
+    /// Density with which sample() picks wi
+    pub fn pdf(&self, normal: &Vect, wo: &Vect, wi: &Vect) -> f64 {
+        match self {
+            Material::Lambertian(_) | Material::Subsurface(..) => {
+                if normal.dot(wo) <= 0f64 {
+                    return 0f64;
+                }
+                cosine_hemisphere_pdf(normal.dot(wi))
+            }
+            Material::Phong(colour, specular, shininess) => {
+                if normal.dot(wo) <= 0f64 {
+                    return 0f64;
+                }
+                let p = highlight_probability(colour, *specular);
+                if *shininess >= MIRROR_SHININESS {
+                    return (1f64 - p) * cosine_hemisphere_pdf(normal.dot(wi));
+                }
+                let h = wo.add(wi).normalise();
+                let highlight_pdf = (shininess + 1f64) / (2f64 * PI)
+                    * normal.dot(&h).max(0f64).powf(*shininess)
+                    / (4f64 * wo.dot(&h).abs().max(crate::EPSILON));
+                (1f64 - p) * cosine_hemisphere_pdf(normal.dot(wi)) + p * highlight_pdf
+            }
+            Material::Principled(principled) => principled.pdf(normal, wo, wi),
+            Material::Mirror | Material::Glass(_) | Material::Interface => 0f64,
+        }
+    }
+
+    /// Pick a direction for light to come from, proportionally to how much
+    /// the material would scatter it towards wo.
+    pub fn sample(&self, normal: &Vect, wo: &Vect, u1: f64, u2: f64) -> Option<BsdfSample> {
+        match self {
+            Material::Lambertian(_) | Material::Subsurface(..) => {
+                let wi = local_to_world(&cosine_sample_hemisphere(u1, u2), normal);
+                let pdf = self.pdf(normal, wo, &wi);
+                if pdf <= 0f64 {
+                    return None;
+                }
+                Some(BsdfSample {
+                    wi,
+                    f: self.eval(normal, wo, &wi),
+                    pdf,
+                    specular: false,
+                })
+            }
+            Material::Phong(colour, specular, shininess) => {
+                let p = highlight_probability(colour, *specular);
+                if u1 < p && *shininess >= MIRROR_SHININESS {
+                    let cos = normal.dot(wo);
+                    if cos <= 0f64 {
+                        return None;
+                    }
+                    return Some(BsdfSample {
+                        wi: reflect(wo, normal),
+                        f: Vect(1.0, 1.0, 1.0).scalar_mul(&(specular / (p * cos))),
+                        pdf: 1f64,
+                        specular: true,
+                    });
+                }
+                let wi = if u1 < p {
+                    // Half vectors distributed like the highlight
+                    let cos_theta = (u1 / p).powf(1f64 / (shininess + 1f64));
+                    let sin_theta = (1f64 - cos_theta * cos_theta).max(0f64).sqrt();
+                    let phi = 2f64 * PI * u2;
+                    let h = local_to_world(
+                        &Vect(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta),
+                        normal,
+                    );
+                    reflect(wo, &h)
+                } else {
+                    let u1 = (u1 - p) / (1f64 - p);
+                    local_to_world(&cosine_sample_hemisphere(u1, u2), normal)
+                };
+                let pdf = self.pdf(normal, wo, &wi);
+                if normal.dot(&wi) <= 0f64 || pdf <= 0f64 {
+                    return None;
+                }
+                Some(BsdfSample {
+                    wi,
+                    f: self.eval(normal, wo, &wi),
+                    pdf,
+                    specular: false,
+                })
+            }
+            Material::Principled(principled) => principled.sample(normal, wo, u1, u2),
+            Material::Mirror => {
+                let cos = normal.dot(wo);
+                Some(BsdfSample {
+                    wi: reflect(wo, normal),
+                    f: Vect(1.0, 1.0, 1.0).scalar_mul(&(1f64 / cos.abs())),
+                    pdf: 1f64,
+                    specular: true,
+                })
+            }
+            Material::Glass(ior) => {
+                // Reflect or refract randomly in proportion to how much
+                // light goes each way, so the weight is always one
+                let reflectance = fresnel_dielectric(normal.dot(wo), *ior);
+                let wi = match refract(wo, normal, *ior) {
+                    Some(wi) if u1 >= reflectance => wi,
+                    _ => reflect(wo, normal),
+                };
+                Some(BsdfSample {
+                    wi,
+                    f: Vect(1.0, 1.0, 1.0).scalar_mul(&(1f64 / wi.dot(normal).abs())),
+                    pdf: 1f64,
+                    specular: true,
+                })
+            }
+            Material::Interface => Some(BsdfSample {
+                wi: wo.scalar_mul(&-1f64),
+                f: Vect(1.0, 1.0, 1.0).scalar_mul(&(1f64 / normal.dot(wo).abs())),
+                pdf: 1f64,
+                specular: true,
+            }),
+        }
+    }
+}
+
+/// Chance of a Phong material sampling its highlight rather than the
+/// diffuse part, going by how much each reflects
+fn highlight_probability(colour: &Vect, specular: f64) -> f64 {
+    let diffuse = colour.0.max(colour.1).max(colour.2);
+    if specular + diffuse <= 0f64 {
+        return 0f64;
+    }
+    specular / (specular + diffuse)
+}
+
+/// wo mirrored about the normal
+pub fn reflect(wo: &Vect, normal: &Vect) -> Vect {
+    normal.scalar_mul(&(2f64 * normal.dot(wo))).sub(wo)
+}
+
+/// Direction light coming from wo continues in after going through the
+/// surface of a material with index of refraction ior, from whichever side
+/// of the normal wo is on. None on total internal reflection.
+pub fn refract(wo: &Vect, normal: &Vect, ior: f64) -> Option<Vect> {
+    let cos_i = normal.dot(wo);
+    let (normal, eta, cos_i) = if cos_i > 0f64 {
+        (*normal, 1f64 / ior, cos_i)
+    } else {
+        (normal.scalar_mul(&-1f64), ior, -cos_i)
+    };
+    let sin_t_sq = eta * eta * (1f64 - cos_i * cos_i);
+    if sin_t_sq >= 1f64 {
+        return None;
+    }
+    let cos_t = (1f64 - sin_t_sq).sqrt();
+    Some(
+        wo.scalar_mul(&-eta)
+            .add(&normal.scalar_mul(&(eta * cos_i - cos_t))),
+    )
+}
+
+/// Fraction of unpolarised light reflected off the surface of a material
+/// with index of refraction ior, given the cosine between the normal and
+/// the direction the light arrives from. Negative cosines are on the inside.
+pub fn fresnel_dielectric(cos_i: f64, ior: f64) -> f64 {
+    let (eta_i, eta_t, cos_i) = if cos_i > 0f64 {
+        (1f64, ior, cos_i)
+    } else {
+        (ior, 1f64, -cos_i)
+    };
+    let sin_t = eta_i / eta_t * (1f64 - cos_i * cos_i).max(0f64).sqrt();
+    if sin_t >= 1f64 {
+        return 1f64;
+    }
+    let cos_t = (1f64 - sin_t * sin_t).sqrt();
+    let parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2f64
+}
+
+#[test]
+fn lambertian_test() {
+    // Energy conservation: a white Lambertian surface reflects all the light
+    // it receives, and the estimator weight is exactly the albedo.
+    let white = Material::Lambertian(Vect(1.0, 1.0, 1.0));
+    let normal = Vect(0.0, 1.0, 0.0);
+    let wo = Vect(0.0, 1.0, 1.0).normalise();
+    let n = 100;
+    let mut reflected = 0f64;
+    for i in 0..n {
+        for j in 0..n {
+            let u1 = (i as f64 + 0.5) / n as f64;
+            let u2 = (j as f64 + 0.5) / n as f64;
+            let s = white.sample(&normal, &wo, u1, u2).unwrap();
+            let w = s.weight(&normal);
+            assert!((w.0 - 1f64).abs() < 1e-9);
+            assert!((s.pdf - white.pdf(&normal, &wo, &s.wi)).abs() < 1e-12);
+            // Integrate f cos over the hemisphere with uniform sampling too
+            let wi = local_to_world(&uniform_sample_hemisphere(u1, u2), &normal);
+            reflected +=
+                white.eval(&normal, &wo, &wi).0 * wi.dot(&normal) / uniform_hemisphere_pdf();
+        }
+    }
+    assert!((reflected / (n * n) as f64 - 1f64).abs() < 1e-3);
+}
+
+#[test]
+fn glass_test() {
+    let normal = Vect(0.0, 1.0, 0.0);
+    // Head on, 4% of the light is reflected off glass
+    assert!((fresnel_dielectric(1f64, 1.5) - 0.04).abs() < 1e-12);
+    let wo = Vect(1.0, 1.0, 0.0).normalise();
+    let wi = refract(&wo, &normal, 1.5).unwrap();
+    // Snell's law, and going back out undoes it
+    let sin = |v: &Vect| (1f64 - v.dot(&normal).powi(2)).sqrt();
+    assert!((sin(&wo) - 1.5 * sin(&wi)).abs() < 1e-12);
+    let back = refract(&wi, &normal, 1.5).unwrap();
+    assert!(back.sub(&wo).norm() < 1e-12);
+    // Beyond the critical angle inside the glass, everything is reflected
+    let grazing = Vect(1.0, -0.1, 0.0).normalise();
+    assert!(refract(&grazing, &normal, 1.5).is_none());
+    assert_eq!(fresnel_dielectric(grazing.dot(&normal), 1.5), 1f64);
+}
+
+#[test]
+fn phong_test() {
+    // The sampled directions have the density pdf() says, so integrating
+    // f cos / pdf gives the same reflectance as uniform sampling
+    let phong = Material::Phong(Vect(0.3, 0.3, 0.3), 0.5, 20f64);
+    let normal = Vect(0.0, 1.0, 0.0);
+    let wo = Vect(0.0, 1.0, 1.0).normalise();
+    let n = 400;
+    let (mut sampled, mut uniform) = (0f64, 0f64);
+    for i in 0..n {
+        for j in 0..n {
+            let u1 = (i as f64 + 0.5) / n as f64;
+            let u2 = (j as f64 + 0.5) / n as f64;
+            if let Some(s) = phong.sample(&normal, &wo, u1, u2) {
+                sampled += s.weight(&normal).0;
+            }
+            let wi = local_to_world(&uniform_sample_hemisphere(u1, u2), &normal);
+            uniform += phong.eval(&normal, &wo, &wi).0 * wi.dot(&normal) / uniform_hemisphere_pdf();
+        }
+    }
+    let (sampled, uniform) = (sampled / (n * n) as f64, uniform / (n * n) as f64);
+    assert!(uniform < 1f64);
+    assert!((sampled - uniform).abs() < 0.01 * uniform);
+}
+
+#[test]
+fn mirror_phong_test() {
+    // Past MIRROR_SHININESS the highlight is a perfect reflection, which
+    // eval() leaves out, and the weights still add up to what it reflects
+    let phong = Material::Phong(Vect(0.3, 0.3, 0.3), 0.5, MIRROR_SHININESS);
+    let normal = Vect(0.0, 1.0, 0.0);
+    let wo = Vect(0.0, 1.0, 1.0).normalise();
+    let mirrored = reflect(&wo, &normal);
+    let diffuse = phong.eval(&normal, &wo, &mirrored).0;
+    assert!((diffuse - 0.3 / PI).abs() < 1e-12);
+    let n = 400;
+    let mut reflected = 0f64;
+    for i in 0..n {
+        for j in 0..n {
+            let u1 = (i as f64 + 0.5) / n as f64;
+            let u2 = (j as f64 + 0.5) / n as f64;
+            let s = phong.sample(&normal, &wo, u1, u2).unwrap();
+            if s.specular {
+                assert!(s.wi.sub(&mirrored).norm() < 1e-12);
+            } else {
+                assert!((s.pdf - phong.pdf(&normal, &wo, &s.wi)).abs() < 1e-12);
+            }
+            reflected += s.weight(&normal).0;
+        }
+    }
+    assert!((reflected / (n * n) as f64 - 0.8).abs() < 1e-9);
+}
@@ -1,13 +1,104 @@
+use crate::checkpoint;
+use crate::film::{AovPixel, Film, Pixel};
+use crate::photon;
 use crate::ray::*;
+use crate::sampler;
+use crate::settings::{Integrator, RenderSettings};
+use crate::stats::RenderStats;
 use crate::typedefs::Scene;
 use crate::vect::*;
-use image::{Rgb, RgbImage};
-use std::cmp::{max, min};
+use image::ImageFormat;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
 
-const GAMMA: f64 = 0.45;
+/// How often the progress callback gets called at most
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A row of the film with the samples a worker added to it
+struct RenderedRow {
+    row: u32,
+    pixels: Vec<Pixel>,
+    aovs: Option<Vec<AovPixel>>,
+    /// Light traced onto pixels anywhere in the film
+    splats: Vec<((u32, u32), Vect)>,
+    stats: RenderStats,
+}
+
+type RowSender = mpsc::Sender<RenderedRow>;
+
+/// The rendered film, how much work it took and whether it's all there
+pub struct RenderOutput {
+    pub film: Film,
+    pub stats: RenderStats,
+    pub time: Duration,
+    pub status: RenderStatus,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RenderStatus {
+    /// Got every sample asked for, or ran out of budget
+    Finished,
+    /// Stopped through the cancel token, the film has whatever was done
+    /// by then
+    Cancelled,
+    /// Some rows were lost to workers that crashed
+    Failed(String),
+}
+
+/// Lets a render be stopped from another thread. Clones share the same
+/// flag.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far along a render is, handed to the progress callback
+pub struct Progress {
+    /// The pass over the image being rendered. There's only the one unless
+    /// rendering progressively or on a budget.
+    pub pass: u32,
+    pub samples_done: u64,
+    /// Path and shadow rays per second so far
+    pub rays_per_second: f64,
+    pub elapsed: Duration,
+    /// How much of the render is done, between 0 and 1
+    pub fraction: f64,
+    /// Time left if the rest of the render goes as fast as it has so far
+    pub eta: Duration,
+    /// Set for the last call, once the render is finished
+    pub done: bool,
+}
+
+/// What a pass over the image does with each pixel: take samples until it
+/// has until of them, stopping early if the deadline passes or the render
+/// gets cancelled.
+#[derive(Clone)]
+struct Pass {
+    until: u32,
+    deadline: Option<Instant>,
+    cancel: CancelToken,
+}
+
+impl Pass {
+    fn stopped(&self) -> bool {
+        self.cancel.is_cancelled() || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+}
 
 /// The Camera type is a product type that contains the position of the
 /// camera, the direction its facing, the direction that's "up" from the
@@ -15,6 +106,7 @@ const GAMMA: f64 = 0.45;
 /// viewing angle of the camera, which determines how much of the world the
 /// camera sees.
 ///           pos, screen_top_left, step_right, step_down
+#[derive(Clone, Copy)]
 pub struct Camera(Vect, Vect, Vect, Vect);
 
 /// Create a new camera with the given position, direction,
@@ -37,73 +129,785 @@ pub fn new(pos: Vect, dir: Vect, up: Vect, angle: f64) -> Camera {
         pos.add(&dir)
             .add(&up.scalar_mul(&(screen_heigth / 2f64)))
             .add(&left.scalar_mul(&(screen_width / 2f64))),
-        left.scalar_mul(&(-1 as f64))
+        left.scalar_mul(&-1f64)
             .scalar_mul(&(screen_width / (crate::IMAGE_WIDTH as f64))),
-        up.scalar_mul(&(-1 as f64))
+        up.scalar_mul(&-1f64)
             .scalar_mul(&(screen_heigth / (crate::IMAGE_HEIGTH as f64))),
     )
 }
 
 impl Camera {
-    pub fn ray(&self, row: &u32, col: &u32) -> Ray {
+    pub fn pos(&self) -> Vect {
+        self.0
+    }
+
+    /// Unit vector the camera is facing. The screen is at distance one in
+    /// front of the camera.
+    fn forward(&self) -> Vect {
+        let Camera(cam_pos, screen_top_left, step_right, step_down) = self;
+        screen_top_left
+            .add(&step_right.scalar_mul(&(crate::IMAGE_WIDTH as f64 / 2f64)))
+            .add(&step_down.scalar_mul(&(crate::IMAGE_HEIGTH as f64 / 2f64)))
+            .sub(cam_pos)
+    }
+
+    /// Distance from the camera to pos along the viewing direction
+    pub fn depth(&self, pos: &Vect) -> f64 {
+        pos.sub(&self.0).dot(&self.forward())
+    }
+
+    /// The pixel, as (row, col), that pos shows up in, if it's in view
+    pub fn raster(&self, pos: &Vect) -> Option<(u32, u32)> {
+        let Camera(cam_pos, screen_top_left, step_right, step_down) = self;
+        let depth = self.depth(pos);
+        if depth <= 0f64 {
+            return None;
+        }
+        let on_screen = cam_pos
+            .add(&pos.sub(cam_pos).scalar_mul(&(1f64 / depth)))
+            .sub(screen_top_left);
+        let row = on_screen.dot(step_down) / step_down.norm_sq();
+        let col = on_screen.dot(step_right) / step_right.norm_sq();
+        if row < 0f64
+            || col < 0f64
+            || row >= crate::IMAGE_HEIGTH as f64
+            || col >= crate::IMAGE_WIDTH as f64
+        {
+            return None;
+        }
+        Some((row as u32, col as u32))
+    }
+
+    /// Density of camera rays going in direction dir with respect to solid
+    /// angle, were they spread evenly over the whole screen. Zero out of
+    /// view.
+    pub fn pdf(&self, dir: &Vect) -> f64 {
+        let cos = dir.dot(&self.forward());
+        if self.raster(&self.0.add(dir)).is_none() {
+            return 0f64;
+        }
+        1f64 / (self.screen_area() * cos * cos * cos)
+    }
+
+    fn screen_area(&self) -> f64 {
+        let Camera(_, _, step_right, step_down) = self;
+        step_right.norm() * step_down.norm() * (crate::IMAGE_WIDTH * crate::IMAGE_HEIGTH) as f64
+    }
+
+    /// Ray through the point (row, col) of the image. Pixel (i, j) covers
+    /// [i, i + 1) x [j, j + 1).
+    pub fn ray(&self, row: &f64, col: &f64) -> Ray {
         let Camera(pos, screen_top_left, step_right, step_down) = self;
         Ray(
             *pos,
             screen_top_left
-                .add(&step_down.scalar_mul(&(*row as f64)))
-                .add(&step_right.scalar_mul(&(*col as f64)))
-                .sub(&pos)
+                .add(&step_down.scalar_mul(row))
+                .add(&step_right.scalar_mul(col))
+                .sub(pos)
                 .normalise(),
         )
     }
 
-    pub fn render(&self, scene: Arc<Scene>, nrays: u32, depth: u8) -> RgbImage {
+    /// Add samples to film until it has the ones the settings ask for or the
+    /// budget runs out, calling progress every now and then as rows get
+    /// finished, and a last time at the end. Progressive renders and ones on
+    /// a budget go over the image once per sample, progressive ones saving
+    /// snapshots along the way. Every pixel is the average of however many
+    /// samples it got, so stopping partway through a pass is fine, be it
+    /// because of the budget or the cancel token.
+    pub fn render(
+        &self,
+        scene: Arc<Scene>,
+        settings: &RenderSettings,
+        mut film: Film,
+        cancel: &CancelToken,
+        progress: &mut dyn FnMut(&Progress),
+    ) -> RenderOutput {
         println!("Starting render");
         let t0 = Instant::now();
-        let (tx, rx) = mpsc::channel();
-        self.render_rays(scene, nrays, depth, tx);
-        println!("finished tracing");
-        println!("tracing complete in {}ms", t0.elapsed().as_millis());
-        let mut res = RgbImage::new(crate::IMAGE_WIDTH, crate::IMAGE_HEIGTH);
-        for ((row, col), (r, g, b)) in rx.iter() {
-            res.put_pixel(col, row, Rgb([r, g, b]));
+        let mut settings = settings.clone();
+        let mut stats = RenderStats::default();
+        if let Integrator::PhotonMapping { photons, radius } = settings.integrator {
+            let photon_map = photon::emit(
+                &scene,
+                photons,
+                radius,
+                settings.depth,
+                settings.seed,
+                &mut stats,
+            );
+            println!(
+                "Kept {} caustic photons out of {}",
+                photon_map.len(),
+                photons
+            );
+            settings.photon_map = Some(Arc::new(photon_map));
+        }
+        let settings = Arc::new(settings);
+        let tpool = threadpool::Builder::new()
+            .num_threads(settings.threads)
+            .build();
+        let passes = match (&settings.progressive, &settings.budget) {
+            (None, None) => 1,
+            _ => settings.nrays,
+        };
+        let deadline = settings
+            .budget
+            .as_ref()
+            .and_then(|budget| budget.seconds)
+            .map(|secs| t0 + Duration::from_secs_f64(secs));
+        let max_noise = settings.budget.as_ref().and_then(|budget| budget.max_noise);
+        // Skip the passes a resumed film has been through already
+        let first_pass = match film.pixels.iter().map(|p| p.samples).min() {
+            Some(samples) if passes > 1 => samples.min(passes),
+            _ => 0,
+        };
+        // How far along the noise is towards max_noise, as of the last pass
+        let mut noise_fraction = 0f64;
+        let mut last_pass = first_pass.saturating_sub(1);
+        let mut status = RenderStatus::Finished;
+        let mut last_report = t0;
+        let mut last_snapshot = t0;
+        for pass in first_pass..passes {
+            last_pass = pass;
+            let (tx, rx) = mpsc::channel();
+            let todo = Pass {
+                // In u64, as budgets allow for many passes of many rays
+                until: (settings.nrays as u64 * (pass as u64 + 1) / passes as u64) as u32,
+                deadline,
+                cancel: cancel.clone(),
+            };
+            self.render_rows(&tpool, &scene, &settings, &film, todo, tx);
+            let mut rows_done = 0;
+            // Splats land anywhere on the film, so they're added in row order
+            // once the pass is done, for the sums to come out the same every
+            // time whichever row finishes first
+            let mut row_splats = vec![Vec::new(); film.height as usize];
+            for rendered in rx.iter() {
+                film.set_row(rendered.row, &rendered.pixels, rendered.aovs.as_deref());
+                row_splats[rendered.row as usize] = rendered.splats;
+                stats.add(&rendered.stats);
+                rows_done += 1;
+                if last_report.elapsed() >= PROGRESS_INTERVAL {
+                    last_report = Instant::now();
+                    let elapsed = t0.elapsed();
+                    // Whichever limit looks like it'll be hit first
+                    let fraction = ((pass - first_pass) as f64
+                        + rows_done as f64 / film.height as f64)
+                        / (passes - first_pass) as f64;
+                    let fraction = match settings.budget.as_ref().and_then(|b| b.seconds) {
+                        Some(secs) => fraction.max(elapsed.as_secs_f64() / secs),
+                        None => fraction,
+                    }
+                    .max(noise_fraction)
+                    .min(1f64);
+                    progress(&Progress {
+                        pass,
+                        samples_done: stats.camera_rays,
+                        rays_per_second: stats.total_rays() as f64 / elapsed.as_secs_f64(),
+                        elapsed,
+                        fraction,
+                        eta: elapsed.mul_f64((1f64 - fraction) / fraction),
+                        done: false,
+                    });
+                }
+            }
+            for splats in &row_splats {
+                film.add_splats(splats);
+            }
+            // A worker that panics drops its sender without sending its row
+            if rows_done < film.height {
+                status = RenderStatus::Failed(format!(
+                    "{} rows were lost in pass {}",
+                    film.height - rows_done,
+                    pass + 1
+                ));
+                break;
+            }
+            if cancel.is_cancelled() {
+                status = RenderStatus::Cancelled;
+                break;
+            }
+            if let Some(progressive) = &settings.progressive {
+                let due = progressive
+                    .snapshot_passes
+                    .is_some_and(|n| (pass + 1) % n == 0)
+                    || progressive
+                        .snapshot_seconds
+                        .is_some_and(|secs| last_snapshot.elapsed().as_secs_f64() >= secs);
+                if due {
+                    last_snapshot = Instant::now();
+                    if let Err(e) = film
+                        .image()
+                        .save_with_format(&progressive.snapshot, ImageFormat::Png)
+                    {
+                        println!("\nCouldn't save snapshot: {}", e);
+                    }
+                    save_checkpoint(&film, &settings);
+                }
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+            if let Some(max_noise) = max_noise {
+                // Noise goes down with the square root of the samples
+                let noise = film.noise();
+                if noise <= max_noise {
+                    break;
+                }
+                noise_fraction = (max_noise / noise).powi(2);
+            }
+        }
+        let time = t0.elapsed();
+        progress(&Progress {
+            pass: last_pass,
+            samples_done: stats.camera_rays,
+            rays_per_second: stats.total_rays() as f64 / time.as_secs_f64(),
+            elapsed: time,
+            fraction: 1f64,
+            eta: Duration::ZERO,
+            done: true,
+        });
+        save_checkpoint(&film, &settings);
+        println!("tracing complete in {}ms", time.as_millis());
+        println!(
+            "{:.2} samples per pixel on average",
+            film.total_samples() as f64 / film.pixels.len() as f64
+        );
+        RenderOutput {
+            film,
+            stats,
+            time,
+            status,
         }
-        res
     }
 
-    fn render_rays(
+    /// Queue up every row of the film on the pool, to be sampled as todo
+    /// says. The rows come back through tx as they get done.
+    fn render_rows(
         &self,
-        scene: Arc<Scene>,
-        nrays: u32,
-        depth: u8,
-        tx: mpsc::Sender<((u32, u32), (u8, u8, u8))>,
+        tpool: &ThreadPool,
+        scene: &Arc<Scene>,
+        settings: &Arc<RenderSettings>,
+        film: &Film,
+        todo: Pass,
+        tx: RowSender,
     ) {
-        let tpool = threadpool::Builder::new()
-            .num_threads(crate::N_THREADS)
-            .thread_stack_size(8000000)
-            .build();
-        for row in 0..crate::IMAGE_HEIGTH {
-            for col in 0..crate::IMAGE_WIDTH {
-                let ray = self.ray(&row, &col);
-                let ntx = tx.clone();
-                let nsp = scene.clone();
-                tpool.execute(move || {
-                    let mut tot_colour = zero();
-                    for _ in 0..nrays {
-                        tot_colour = tot_colour.add(&ray.colour(&*nsp, depth));
+        for row in 0..film.height {
+            let cam = *self;
+            let ntx = tx.clone();
+            let nsp = scene.clone();
+            let nsettings = settings.clone();
+            let mut pixels = film.row(row).to_vec();
+            let mut aovs = film.aov_row(row).map(|aovs| aovs.to_vec());
+            let todo = todo.clone();
+            tpool.execute(move || {
+                let mut stats = RenderStats::default();
+                let mut splats = Vec::new();
+                for (col, pixel) in pixels.iter_mut().enumerate() {
+                    if todo.stopped() {
+                        break;
                     }
-                    let Vect(r, g, b) = tot_colour;
-                    let r = max(0u8, min(255u8, (r / (nrays as f64)).powf(GAMMA) as u8));
-                    let g = max(0u8, min(255u8, (g / (nrays as f64)).powf(GAMMA) as u8));
-                    let b = max(0u8, min(255u8, (b / (nrays as f64)).powf(GAMMA) as u8));
-                    let res = ntx.send(((row, col), (r, g, b)));
-                    match res {
-                        Ok(()) => (),
-                        Err(e) => std::panic!("Thread failed with {}", e),
-                    };
-                });
-            }
+                    sample_pixel(
+                        &cam,
+                        &nsp,
+                        &nsettings,
+                        (row, col as u32),
+                        (pixel, aovs.as_mut().map(|aovs| &mut aovs[col])),
+                        todo.until,
+                        &mut stats,
+                        &mut splats,
+                    );
+                }
+                // The receiver outlives every task, but if it were gone there
+                // would be nobody left to give the row to anyway
+                ntx.send(RenderedRow {
+                    row,
+                    pixels,
+                    aovs,
+                    splats,
+                    stats,
+                })
+                .ok();
+            });
+        }
+    }
+}
+
+/// Save the film to the checkpoint file, if there is one
+fn save_checkpoint(film: &Film, settings: &RenderSettings) {
+    if let Some(ckpt) = &settings.checkpoint {
+        if let Err(e) = checkpoint::save(&ckpt.filename, film, ckpt.scene_hash, settings.seed) {
+            println!("\nCouldn't save checkpoint: {}", e);
+        }
+    }
+}
+
+/// Add samples to the pixel at (row, col) and its AOVs if there are any,
+/// until it has until of them or adaptive sampling decides it has enough.
+/// Light the samples trace onto other pixels gets added to splats.
+#[allow(clippy::too_many_arguments)]
+fn sample_pixel(
+    cam: &Camera,
+    scene: &Scene,
+    settings: &RenderSettings,
+    (row, col): (u32, u32),
+    (pixel, mut aov): (&mut Pixel, Option<&mut AovPixel>),
+    until: u32,
+    stats: &mut RenderStats,
+    splats: &mut Vec<((u32, u32), Vect)>,
+) {
+    let mut sampler = sampler::new(settings.sampler, settings.seed, settings.nrays);
+    while pixel.samples < until && !pixel.converged(&settings.adaptive) {
+        sampler.start_sample(row, col, pixel.samples);
+        let (dx, dy) = sampler.get_2d();
+        let ray = cam.ray(&(row as f64 + dy), &(col as f64 + dx));
+        let mut path = settings
+            .integrator
+            .trace(&ray, cam, scene, settings, &mut *sampler, stats);
+        pixel.add_sample(&path.colour);
+        splats.append(&mut path.splats);
+        if let Some(aov) = aov.as_deref_mut() {
+            let depth = path
+                .first_hit
+                .as_ref()
+                .map_or(0f64, |hit| cam.depth(&hit.pos));
+            aov.add_sample(&path, depth);
         }
-        tpool.join();
     }
 }
+
+/// All the samples the settings ask for of the pixel at (row, col)
+#[cfg(test)]
+fn pixel_colour(
+    cam: &Camera,
+    scene: &Scene,
+    settings: &RenderSettings,
+    (row, col): (u32, u32),
+) -> (Pixel, RenderStats) {
+    let mut pixel = Pixel::new();
+    let mut stats = RenderStats::default();
+    sample_pixel(
+        cam,
+        scene,
+        settings,
+        (row, col),
+        (&mut pixel, None),
+        settings.nrays,
+        &mut stats,
+        &mut Vec::new(),
+    );
+    (pixel, stats)
+}
+
+#[test]
+fn deterministic_render_test() {
+    use crate::sampler::SamplerType;
+    let scene = crate::scene_loader::load_scene("scene.toml").unwrap().0;
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 3f64,
+    );
+    for sampler in [
+        SamplerType::Independent,
+        SamplerType::Stratified,
+        SamplerType::Halton,
+        SamplerType::Sobol,
+    ] {
+        let settings = RenderSettings {
+            sampler,
+            ..RenderSettings::default()
+        };
+        let first = pixel_colour(&cam, &scene, &settings, (500, 400)).0.mean();
+        assert_eq!(
+            first,
+            pixel_colour(&cam, &scene, &settings, (500, 400)).0.mean()
+        );
+        let reseeded = RenderSettings {
+            seed: 42,
+            ..settings
+        };
+        assert_ne!(
+            first,
+            pixel_colour(&cam, &scene, &reseeded, (500, 400)).0.mean()
+        );
+    }
+}
+
+#[test]
+fn adaptive_sampling_test() {
+    use crate::settings::AdaptiveSettings;
+    let scene = crate::scene_loader::load_scene("scene.toml").unwrap().0;
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 3f64,
+    );
+    let settings = RenderSettings {
+        nrays: 64,
+        adaptive: Some(AdaptiveSettings {
+            min_rays: 4,
+            max_error: 1e-9,
+        }),
+        ..RenderSettings::default()
+    };
+    // An impossible error target takes every sample
+    assert_eq!(
+        pixel_colour(&cam, &scene, &settings, (500, 400)).0.samples,
+        64
+    );
+    let settings = RenderSettings {
+        adaptive: Some(AdaptiveSettings {
+            min_rays: 4,
+            max_error: f64::INFINITY,
+        }),
+        ..settings
+    };
+    let (pixel, stats) = pixel_colour(&cam, &scene, &settings, (500, 400));
+    assert_eq!(pixel.samples, 4);
+    assert_eq!(stats.camera_rays, 4);
+}
+
+#[test]
+fn progressive_test() {
+    // Taking the samples one pass at a time gives the same pixel as taking
+    // them all at once
+    let scene = crate::scene_loader::load_scene("scene.toml").unwrap().0;
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 3f64,
+    );
+    let settings = RenderSettings::default();
+    let mut pixel = Pixel::new();
+    let mut stats = RenderStats::default();
+    for pass in 0..settings.nrays {
+        sample_pixel(
+            &cam,
+            &scene,
+            &settings,
+            (500, 400),
+            (&mut pixel, None),
+            pass + 1,
+            &mut stats,
+            &mut Vec::new(),
+        );
+        assert_eq!(pixel.samples, pass + 1);
+    }
+    assert_eq!(
+        pixel.sum,
+        pixel_colour(&cam, &scene, &settings, (500, 400)).0.sum
+    );
+}
+
+#[test]
+fn cancel_test() {
+    let scene = crate::scene_loader::load_scene("scene.toml").unwrap().0;
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 3f64,
+    );
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let mut reports = 0;
+    let output = cam.render(
+        Arc::new(scene),
+        &RenderSettings::default(),
+        Film::new(crate::IMAGE_WIDTH, crate::IMAGE_HEIGTH),
+        &cancel,
+        &mut |progress| {
+            reports += 1;
+            assert!(progress.done);
+        },
+    );
+    assert_eq!(output.status, RenderStatus::Cancelled);
+    assert_eq!(output.film.total_samples(), 0);
+    assert_eq!(reports, 1);
+}
+
+#[test]
+fn progress_test() {
+    // The last report is the only one marked done and counts every sample.
+    // The light behind the wall never gets a shadow ray, so only the
+    // environment does, once per sample.
+    use crate::environment::Constant;
+    use crate::light::Pointlight;
+    use crate::plane::Plane;
+    use crate::typedefs::Material;
+    let scene: Scene = (
+        vec![Box::new(Plane {
+            point: Vect(0.0, 0.0, 10.0),
+            normal: Vect(0.0, 0.0, -1.0),
+            material: Material::Lambertian(Vect(0.5, 0.5, 0.5)),
+            surface_map: None,
+        })],
+        vec![Box::new(Pointlight {
+            pos: Vect(0.0, 2.0, 20.0),
+            intensity: 1000f64,
+        })],
+        Box::new(Constant(zero())),
+        None,
+    );
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 3f64,
+    );
+    let settings = RenderSettings {
+        nrays: 2,
+        threads: 1,
+        ..RenderSettings::default()
+    };
+    let mut reports = Vec::new();
+    let output = cam.render(
+        Arc::new(scene),
+        &settings,
+        Film::new(8, 2),
+        &CancelToken::new(),
+        &mut |progress| reports.push((progress.samples_done, progress.fraction, progress.done)),
+    );
+    assert_eq!(output.status, RenderStatus::Finished);
+    assert_eq!(reports.last(), Some(&(32, 1f64, true)));
+    assert!(reports[..reports.len() - 1].iter().all(|r| !r.2));
+    assert_eq!(output.stats.camera_rays, 32);
+    assert_eq!(output.stats.shadow_rays, 32);
+}
+
+#[test]
+fn budget_test() {
+    // A wall lit by a uniform environment looks the same in every sample,
+    // so any noise target is met as soon as the noise can be measured, at
+    // two samples. A time limit stops the render long before its samples
+    // are all taken.
+    use crate::environment::Constant;
+    use crate::plane::Plane;
+    use crate::settings::Budget;
+    use crate::typedefs::Material;
+    let scene: Scene = (
+        vec![Box::new(Plane {
+            point: Vect(0.0, 0.0, 10.0),
+            normal: Vect(0.0, 0.0, -1.0),
+            material: Material::Lambertian(Vect(0.5, 0.5, 0.5)),
+            surface_map: None,
+        })],
+        vec![],
+        Box::new(Constant(Vect(1.0, 1.0, 1.0))),
+        None,
+    );
+    let scene = Arc::new(scene);
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 3f64,
+    );
+    let settings = RenderSettings {
+        nrays: 1 << 20,
+        threads: 1,
+        budget: Some(Budget {
+            seconds: None,
+            max_noise: Some(0.01),
+        }),
+        ..RenderSettings::default()
+    };
+    let output = cam.render(
+        scene.clone(),
+        &settings,
+        Film::new(8, 2),
+        &CancelToken::new(),
+        &mut |_| (),
+    );
+    assert_eq!(output.status, RenderStatus::Finished);
+    assert!(output.film.pixels.iter().all(|p| p.samples == 2));
+    let settings = RenderSettings {
+        budget: Some(Budget {
+            seconds: Some(0.1),
+            max_noise: None,
+        }),
+        ..settings
+    };
+    let output = cam.render(
+        scene,
+        &settings,
+        Film::new(8, 2),
+        &CancelToken::new(),
+        &mut |_| (),
+    );
+    assert_eq!(output.status, RenderStatus::Finished);
+    assert!(output.time.as_secs_f64() < 5f64);
+    assert!(output.film.total_samples() > 0);
+    assert!(output.film.total_samples() < 16 << 20);
+}
+
+#[test]
+fn depth_test() {
+    // Depth is measured along the viewing direction, not to the camera
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 3f64,
+    );
+    assert!((cam.depth(&Vect(3.0, 5.0, 10.0)) - 10f64).abs() < 1e-12);
+    assert!((cam.depth(&Vect(0.0, 2.0, -4.0)) + 4f64).abs() < 1e-12);
+    assert_eq!(
+        cam.raster(&Vect(0.0, 2.0, 10.0)),
+        Some((crate::IMAGE_HEIGTH / 2, crate::IMAGE_WIDTH / 2))
+    );
+    assert_eq!(cam.raster(&Vect(0.0, 2.0, -4.0)), None);
+}
+
+#[test]
+fn bidirectional_test() {
+    // Bidirectional path tracing converges to the same image as path
+    // tracing, light traced onto the film from the lights included. Inside
+    // a closed box lit off-centre and by its glowing ceiling, each quarter
+    // of the image gets the same brightness either way.
+    use crate::light::Pointlight;
+    use crate::plane::Plane;
+    use crate::principled::Principled;
+    use crate::settings::Integrator;
+    use crate::typedefs::Material;
+    let lambertian = Material::Lambertian(Vect(0.3, 0.3, 0.3));
+    let glowing = Material::Principled(Principled {
+        base_colour: Vect(0.3, 0.3, 0.3),
+        metallic: 0f64,
+        roughness: 1f64,
+        specular: 0.5,
+        clearcoat: 0f64,
+        sheen: 0f64,
+        transmission: 0f64,
+        ior: 1.5,
+        emission: Vect(0.2, 0.2, 0.2),
+    });
+    let wall = |normal: Vect, material| -> Box<dyn crate::geometry::Geometry + Send + Sync> {
+        Box::new(Plane {
+            point: normal.scalar_mul(&-1f64),
+            normal,
+            material,
+            surface_map: None,
+        })
+    };
+    let scene: Scene = (
+        vec![
+            wall(Vect(1.0, 0.0, 0.0), lambertian),
+            wall(Vect(-1.0, 0.0, 0.0), lambertian),
+            wall(Vect(0.0, 1.0, 0.0), lambertian),
+            wall(Vect(0.0, -1.0, 0.0), glowing),
+            wall(Vect(0.0, 0.0, 1.0), lambertian),
+            wall(Vect(0.0, 0.0, -1.0), lambertian),
+        ],
+        vec![Box::new(Pointlight {
+            pos: Vect(0.4, 0.6, 0.3),
+            intensity: 10f64,
+        })],
+        Box::new(crate::environment::Constant(zero())),
+        None,
+    );
+    let scene = Arc::new(scene);
+    let cam = new(
+        Vect(0f64, -0.5f64, -0.9f64),
+        Vect(0f64, 0f64, 1f64),
+        Vect(0f64, 1f64, 0f64),
+        std::f64::consts::PI / 2f64,
+    );
+    let quarters = |integrator| {
+        let settings = RenderSettings {
+            nrays: 1,
+            threads: 1,
+            integrator,
+            ..RenderSettings::default()
+        };
+        let mut film = Film::new(crate::IMAGE_WIDTH, crate::IMAGE_HEIGTH);
+        if integrator == Integrator::Bidirectional {
+            film.enable_splats();
+        }
+        let output = cam.render(
+            scene.clone(),
+            &settings,
+            film,
+            &CancelToken::new(),
+            &mut |_| (),
+        );
+        let mut sums = [0f64; 4];
+        for (i, colour) in output.film.colours().iter().enumerate() {
+            let (row, col) = (i as u32 / crate::IMAGE_WIDTH, i as u32 % crate::IMAGE_WIDTH);
+            let quarter = 2 * (row * 2 / crate::IMAGE_HEIGTH) + col * 2 / crate::IMAGE_WIDTH;
+            sums[quarter as usize] += colour.luminance();
+        }
+        sums
+    };
+    let path = quarters(Integrator::Path);
+    let bidirectional = quarters(Integrator::Bidirectional);
+    for (p, b) in path.iter().zip(&bidirectional) {
+        assert!((p - b).abs() < 0.01 * p);
+    }
+}
+
+#[test]
+fn small_film_splats_test() {
+    // A film smaller than the image, and not square, only gets the light
+    // traced onto its own corner of the image, and ends up as bright as
+    // path tracing makes it. Rows finishing in any order add up the same.
+    use crate::light::Pointlight;
+    use crate::plane::Plane;
+    use crate::settings::Integrator;
+    use crate::typedefs::Material;
+    let scene: Scene = (
+        vec![Box::new(Plane {
+            point: zero(),
+            normal: Vect(0.0, 1.0, 0.0),
+            material: Material::Lambertian(Vect(0.5, 0.5, 0.5)),
+            surface_map: None,
+        })],
+        vec![Box::new(Pointlight {
+            pos: Vect(-1.4, 0.3, 1.8),
+            intensity: 10f64,
+        })],
+        Box::new(crate::environment::Constant(zero())),
+        None,
+    );
+    let scene = Arc::new(scene);
+    let cam = new(
+        Vect(0f64, 2f64, 0f64),
+        Vect(0f64, -1f64, 0f64),
+        Vect(0f64, 0f64, 1f64),
+        std::f64::consts::PI / 2f64,
+    );
+    let render = |integrator, threads| {
+        let settings = RenderSettings {
+            nrays: 16,
+            threads,
+            integrator,
+            ..RenderSettings::default()
+        };
+        let mut film = Film::new(300, 100);
+        film.enable_splats();
+        let output = cam.render(
+            scene.clone(),
+            &settings,
+            film,
+            &CancelToken::new(),
+            &mut |_| (),
+        );
+        output.film.colours()
+    };
+    let total = |colours: Vec<Vect>| colours.iter().map(|c| c.luminance()).sum::<f64>();
+    let path = total(render(Integrator::Path, 1));
+    let colours = render(Integrator::Bidirectional, 4);
+    assert!(colours == render(Integrator::Bidirectional, 4));
+    let bidirectional = total(colours);
+    assert!(
+        (path - bidirectional).abs() < 0.02 * path,
+        "{} {}",
+        path,
+        bidirectional
+    );
+}
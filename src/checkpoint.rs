@@ -0,0 +1,204 @@
+//! Checkpoint files hold everything needed to carry on with a render: the
+//! sums and sample counts of every pixel, the seed, and a hash of the scene.
+//! The samplers only depend on the seed and the index of the sample, so
+//! with the sample counts that's all the random state there is.
+//!
+//! The format is little endian: the magic bytes "RTCK", a u32 version, the
+//! u64 scene hash, the u64 seed, u32 width and height, then for each pixel
+//! row by row the red, green and blue sums and the sum of squares as f64
+//! followed by the u32 sample count. After that comes a u8 that is one if
+//! the AOVs follow, again pixel by pixel: the u32 sample and hit counts, the
+//! depth sum, then the normal, albedo, direct and indirect sums as three f64
+//! each, and the u32 object index, u32::MAX for none. Last is a u8 that is
+//! one if the light traced from the lights follows, as three f64 per pixel.
+
+use crate::film::{AovPixel, Film, Pixel};
+use crate::vect::Vect;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, Error};
+
+const MAGIC: &[u8; 4] = b"RTCK";
+const VERSION: u32 = 3;
+
+/// 64 bit FNV-1a, which unlike the standard library's hasher is guaranteed
+/// to give the same hash in every build.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn save(filename: &str, film: &Film, scene_hash: u64, seed: u64) -> Result<(), Error> {
+    let mut f = BufWriter::new(File::create(filename)?);
+    f.write_all(MAGIC)?;
+    f.write_all(&VERSION.to_le_bytes())?;
+    f.write_all(&scene_hash.to_le_bytes())?;
+    f.write_all(&seed.to_le_bytes())?;
+    f.write_all(&film.width.to_le_bytes())?;
+    f.write_all(&film.height.to_le_bytes())?;
+    for pixel in &film.pixels {
+        let Vect(r, g, b) = pixel.sum;
+        for x in [r, g, b, pixel.sum_sq] {
+            f.write_all(&x.to_le_bytes())?;
+        }
+        f.write_all(&pixel.samples.to_le_bytes())?;
+    }
+    match &film.aovs {
+        None => f.write_all(&[0u8])?,
+        Some(aovs) => {
+            f.write_all(&[1u8])?;
+            for aov in aovs {
+                f.write_all(&aov.samples.to_le_bytes())?;
+                f.write_all(&aov.hits.to_le_bytes())?;
+                f.write_all(&aov.depth.to_le_bytes())?;
+                for Vect(x, y, z) in [aov.normal, aov.albedo, aov.direct, aov.indirect] {
+                    for c in [x, y, z] {
+                        f.write_all(&c.to_le_bytes())?;
+                    }
+                }
+                f.write_all(&aov.object.unwrap_or(u32::MAX).to_le_bytes())?;
+            }
+        }
+    }
+    match &film.splats {
+        None => f.write_all(&[0u8])?,
+        Some(splats) => {
+            f.write_all(&[1u8])?;
+            for Vect(r, g, b) in splats {
+                for c in [r, g, b] {
+                    f.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    f.flush()
+}
+
+/// The film saved in the checkpoint, as long as it was rendered from the
+/// same scene with the same seed.
+pub fn load(filename: &str, scene_hash: u64, seed: u64) -> Result<Film, Error> {
+    let mut f = BufReader::new(File::open(filename)?);
+    let mut magic = [0u8; 4];
+    f.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut f)? != VERSION {
+        return Err(Error::other("Not a checkpoint file"));
+    }
+    if read_u64(&mut f)? != scene_hash {
+        return Err(Error::other(
+            "Checkpoint was rendered from a different scene or settings",
+        ));
+    }
+    if read_u64(&mut f)? != seed {
+        return Err(Error::other(
+            "Checkpoint was rendered with a different seed",
+        ));
+    }
+    let width = read_u32(&mut f)?;
+    let height = read_u32(&mut f)?;
+    if width != crate::IMAGE_WIDTH || height != crate::IMAGE_HEIGTH {
+        return Err(Error::other("Checkpoint has the wrong image size"));
+    }
+    let mut film = Film::new(width, height);
+    for pixel in film.pixels.iter_mut() {
+        *pixel = Pixel {
+            sum: read_vect(&mut f)?,
+            sum_sq: read_f64(&mut f)?,
+            samples: read_u32(&mut f)?,
+        };
+    }
+    let mut has_aovs = [0u8];
+    f.read_exact(&mut has_aovs)?;
+    if has_aovs[0] == 1 {
+        film.enable_aovs();
+        for aov in film.aovs.iter_mut().flatten() {
+            let samples = read_u32(&mut f)?;
+            let hits = read_u32(&mut f)?;
+            let depth = read_f64(&mut f)?;
+            let [normal, albedo, direct, indirect] = [0; 4].map(|_| read_vect(&mut f));
+            let object = read_u32(&mut f)?;
+            *aov = AovPixel {
+                samples,
+                hits,
+                depth,
+                normal: normal?,
+                albedo: albedo?,
+                direct: direct?,
+                indirect: indirect?,
+                object: if object == u32::MAX {
+                    None
+                } else {
+                    Some(object)
+                },
+            };
+        }
+    }
+    let mut has_splats = [0u8];
+    f.read_exact(&mut has_splats)?;
+    if has_splats[0] == 1 {
+        film.enable_splats();
+        for splat in film.splats.iter_mut().flatten() {
+            *splat = read_vect(&mut f)?;
+        }
+    }
+    Ok(film)
+}
+
+fn read_u32(f: &mut impl Read) -> Result<u32, Error> {
+    let mut bytes = [0u8; 4];
+    f.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(f: &mut impl Read) -> Result<u64, Error> {
+    let mut bytes = [0u8; 8];
+    f.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64(f: &mut impl Read) -> Result<f64, Error> {
+    let mut bytes = [0u8; 8];
+    f.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+fn read_vect(f: &mut impl Read) -> Result<Vect, Error> {
+    Ok(Vect(read_f64(f)?, read_f64(f)?, read_f64(f)?))
+}
+
+#[test]
+fn checkpoint_test() {
+    let mut film = Film::new(crate::IMAGE_WIDTH, crate::IMAGE_HEIGTH);
+    film.pixels[12345].add_sample(&Vect(1.5, 2.0, 0.25));
+    film.pixels[999999].add_sample(&Vect(0.0, 1e10, -3.0));
+    film.enable_aovs();
+    if let Some(aovs) = &mut film.aovs {
+        aovs[12345].object = Some(3);
+        aovs[12345].normal = Vect(0.0, 1.0, 0.0);
+        aovs[12345].hits = 1;
+    }
+    film.enable_splats();
+    film.add_splats(&[((0, 777), Vect(0.5, 0.0, 2.0))]);
+    let filename = std::env::temp_dir().join("rtracer_checkpoint_test.ckpt");
+    let filename = filename.to_str().unwrap();
+    save(filename, &film, 42, 7).unwrap();
+    let loaded = load(filename, 42, 7).unwrap();
+    for (a, b) in film.pixels.iter().zip(loaded.pixels.iter()) {
+        assert_eq!(a.sum, b.sum);
+        assert_eq!(a.sum_sq, b.sum_sq);
+        assert_eq!(a.samples, b.samples);
+    }
+    assert_eq!(film.splats, loaded.splats);
+    let (aovs, loaded_aovs) = (film.aovs.unwrap(), loaded.aovs.unwrap());
+    for (a, b) in aovs.iter().zip(loaded_aovs.iter()) {
+        assert_eq!(a.object, b.object);
+        assert_eq!(a.normal, b.normal);
+        assert_eq!(a.hits, b.hits);
+    }
+    assert!(load(filename, 43, 7).is_err());
+    assert!(load(filename, 42, 8).is_err());
+    std::fs::remove_file(filename).unwrap();
+}
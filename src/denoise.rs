@@ -0,0 +1,180 @@
+//! Edge-avoiding à-trous wavelet filter, from Dammertz et al. 2010,
+//! "Edge-Avoiding À-Trous Wavelet Transform for fast Global Illumination
+//! Filtering". Each iteration blurs the image with a 5x5 B3 spline kernel
+//! whose taps are spread twice as far apart as in the one before, and every
+//! tap is weighted down by how different its colour, normal, albedo and
+//! depth are from the centre pixel's so that edges stay sharp. The colours
+//! are divided by the albedo before filtering and multiplied by it again
+//! after, so that only the light gets blurred and not the surfaces' detail.
+
+use crate::film::{tonemap, AovLayer, Film};
+use crate::settings::DenoiseSettings;
+use crate::vect::*;
+
+const KERNEL: [f64; 5] = [
+    1f64 / 16f64,
+    1f64 / 4f64,
+    3f64 / 8f64,
+    1f64 / 4f64,
+    1f64 / 16f64,
+];
+
+/// The film's image with the noise filtered out, row by row. Without AOVs
+/// there's nothing to tell edges apart with, and the image is returned as
+/// is.
+pub fn denoise(film: &Film, settings: &DenoiseSettings) -> Vec<Vect> {
+    let colours = film.colours();
+    let aovs = match &film.aovs {
+        Some(aovs) => aovs,
+        None => return colours,
+    };
+    let normals: Vec<Vect> = aovs.iter().map(|p| p.layer(AovLayer::Normal)).collect();
+    let albedos: Vec<Vect> = aovs.iter().map(|p| p.layer(AovLayer::Albedo)).collect();
+    let depths: Vec<f64> = aovs.iter().map(|p| p.layer(AovLayer::Depth).0).collect();
+    // Channels without any albedo are filtered as they are
+    let modulation: Vec<Vect> = albedos
+        .iter()
+        .map(|a| {
+            let [r, g, b] = [a.0, a.1, a.2].map(|c| if c > 0f64 { c } else { 1f64 });
+            Vect(r, g, b)
+        })
+        .collect();
+    let mut colours: Vec<Vect> = colours
+        .iter()
+        .zip(&modulation)
+        .map(|(c, m)| Vect(c.0 / m.0, c.1 / m.1, c.2 / m.2))
+        .collect();
+    let (width, height) = (film.width as i64, film.height as i64);
+    let mut sigma_colour = settings.sigma_colour;
+    for iteration in 0..settings.iterations {
+        let step = 1i64 << iteration.min(32);
+        // Once the taps are further apart than the image is big, only the
+        // centre one is left and nothing changes any more
+        if step >= width.max(height) {
+            break;
+        }
+        // Colours are compared as they'll be displayed, scaled to [0, 1]
+        let display: Vec<Vect> = colours
+            .iter()
+            .map(|c| tonemap(c).scalar_mul(&(1f64 / 255f64)))
+            .collect();
+        let mut filtered = vec![zero(); colours.len()];
+        for row in 0..height {
+            for col in 0..width {
+                let i = (row * width + col) as usize;
+                let mut sum = zero();
+                let mut tot_weight = 0f64;
+                for (dy, ky) in KERNEL.iter().enumerate() {
+                    let r = row + (dy as i64 - 2) * step;
+                    if r < 0 || r >= height {
+                        continue;
+                    }
+                    for (dx, kx) in KERNEL.iter().enumerate() {
+                        let c = col + (dx as i64 - 2) * step;
+                        if c < 0 || c >= width {
+                            continue;
+                        }
+                        let j = (r * width + c) as usize;
+                        let weight = ky
+                            * kx
+                            * edge_weight(display[i].sub(&display[j]).norm_sq(), sigma_colour)
+                            * edge_weight(
+                                normals[i].sub(&normals[j]).norm_sq(),
+                                settings.sigma_normal,
+                            )
+                            * edge_weight(
+                                albedos[i].sub(&albedos[j]).norm_sq(),
+                                settings.sigma_albedo,
+                            )
+                            * edge_weight(
+                                depth_difference(depths[i], depths[j]).powi(2),
+                                settings.sigma_depth,
+                            );
+                        sum = sum.add(&colours[j].scalar_mul(&weight));
+                        tot_weight += weight;
+                    }
+                }
+                // The centre tap always has a weight of one times the kernel
+                filtered[i] = sum.scalar_mul(&(1f64 / tot_weight));
+            }
+        }
+        colours = filtered;
+        // Later iterations average over larger areas that are already
+        // smoother, and should stop at smaller colour differences
+        sigma_colour /= 2f64;
+    }
+    colours
+        .iter()
+        .zip(&modulation)
+        .map(|(c, m)| c.pointwise_mul(m))
+        .collect()
+}
+
+/// How much a neighbour counts given the squared difference of a feature
+fn edge_weight(difference_sq: f64, sigma: f64) -> f64 {
+    (-difference_sq / (sigma * sigma)).exp()
+}
+
+/// Difference of two depths relative to the closer one. Pixels where nothing
+/// was hit have infinite depth, and only match each other.
+fn depth_difference(a: f64, b: f64) -> f64 {
+    if a == b {
+        return 0f64;
+    }
+    (a - b).abs() / a.min(b).max(crate::EPSILON)
+}
+
+#[test]
+fn denoise_test() {
+    use crate::ray::{FirstHit, PathSample};
+    use rand::{Rng, SeedableRng};
+    // A noisy grey image, half of it on one object and half on another of a
+    // different colour
+    let mut film = Film::new(32, 32);
+    film.enable_aovs();
+    let mut rng = rand_pcg::Pcg32::seed_from_u64(0);
+    for i in 0..film.pixels.len() {
+        let left = i % 32 < 16;
+        let brightness = if left { 100f64 } else { 1000f64 };
+        let colour = Vect(1.0, 1.0, 1.0).scalar_mul(&(brightness * rng.gen_range(0.5..1.5)));
+        film.pixels[i].add_sample(&colour);
+        let path = PathSample {
+            colour,
+            direct: colour,
+            first_hit: Some(FirstHit {
+                pos: zero(),
+                normal: Vect(0.0, 0.0, -1.0),
+                albedo: Vect(1.0, 1.0, 1.0).scalar_mul(&if left { 0.1 } else { 1.0 }),
+                object: if left { 0 } else { 1 },
+            }),
+            splats: Vec::new(),
+        };
+        film.aovs.as_mut().unwrap()[i].add_sample(&path, 5f64);
+    }
+    let denoised = denoise(&film, &DenoiseSettings::default());
+    let spread = |colours: &[Vect], left: bool| {
+        let side: Vec<f64> = colours
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (i % 32 < 16) == left)
+            .map(|(_, c)| c.0)
+            .collect();
+        let mean = side.iter().sum::<f64>() / side.len() as f64;
+        let var = side.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / side.len() as f64;
+        (mean, var.sqrt())
+    };
+    let noisy: Vec<Vect> = film.pixels.iter().map(|p| p.mean()).collect();
+    for left in [true, false] {
+        let (mean, spread_before) = spread(&noisy, left);
+        let (denoised_mean, spread_after) = spread(&denoised, left);
+        // Less noise, and the two halves don't bleed into each other
+        assert!(spread_after < spread_before / 2f64);
+        assert!((denoised_mean - mean).abs() < 0.05 * mean);
+    }
+    // Taps 32 pixels apart are all off the image already
+    let settings = DenoiseSettings {
+        iterations: 100,
+        ..DenoiseSettings::default()
+    };
+    assert_eq!(denoise(&film, &settings), denoised);
+}
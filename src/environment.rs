@@ -0,0 +1,146 @@
+//! What a ray sees when it leaves the scene without hitting anything.
+//! Directions are unit vectors pointing away from the scene, with y up.
+
+use crate::sampling::*;
+use crate::vect::*;
+use std::f64::consts::PI;
+use std::io::Error;
+
+pub trait Environment {
+    /// Radiance arriving from direction dir
+    fn radiance(&self, dir: &Vect) -> Vect;
+
+    /// Pick a direction to look for light arriving at a surface with the
+    /// given normal in, from two uniform numbers. Returns the direction and
+    /// its pdf with respect to solid angle. Defaults to cosine weighted
+    /// sampling around the normal.
+    fn sample(&self, normal: &Vect, u1: f64, u2: f64) -> (Vect, f64) {
+        let local = cosine_sample_hemisphere(u1, u2);
+        (
+            local_to_world(&local, normal),
+            cosine_hemisphere_pdf(local.2),
+        )
+    }
+}
+
+/// Same colour in every direction
+pub struct Constant(pub Vect);
+
+impl Environment for Constant {
+    fn radiance(&self, _dir: &Vect) -> Vect {
+        self.0
+    }
+}
+
+/// Linear blend between the bottom colour (looking straight down) and the
+/// top colour (looking straight up).
+pub struct Gradient {
+    pub top: Vect,
+    pub bottom: Vect,
+}
+
+impl Environment for Gradient {
+    fn radiance(&self, dir: &Vect) -> Vect {
+        let t = 0.5 * (dir.1 + 1f64);
+        self.bottom
+            .scalar_mul(&(1f64 - t))
+            .add(&self.top.scalar_mul(&t))
+    }
+}
+
+/// Latitude-longitude (equirectangular) environment map. The top row of
+/// the image is straight up, the left edge is the -x direction.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vect>,
+    distribution: Distribution2D,
+}
+
+/// Lat-long image coordinates in [0, 1)^2 to a direction
+fn uv_to_dir(u: f64, v: f64) -> Vect {
+    let phi = u * 2f64 * PI - PI;
+    let theta = v * PI;
+    Vect(
+        theta.sin() * phi.cos(),
+        theta.cos(),
+        theta.sin() * phi.sin(),
+    )
+}
+
+/// Inverse of uv_to_dir
+fn dir_to_uv(dir: &Vect) -> (f64, f64) {
+    let Vect(x, y, z) = *dir;
+    let u = (z.atan2(x) + PI) / (2f64 * PI);
+    let v = y.clamp(-1f64, 1f64).acos() / PI;
+    (u.clamp(0f64, 1f64), v.clamp(0f64, 1f64))
+}
+
+impl EnvironmentMap {
+    /// Load an .hdr or .exr file. Pixel values are multiplied by intensity.
+    pub fn load(filename: &str, intensity: f64) -> Result<EnvironmentMap, Error> {
+        let img = image::open(filename)
+            .map_err(|e| Error::other(format!("Could not load {}: {}", filename, e)))?
+            .into_rgb32f();
+        let pixels = img
+            .pixels()
+            .map(|p| Vect(p[0] as f64, p[1] as f64, p[2] as f64).scalar_mul(&intensity))
+            .collect();
+        Ok(EnvironmentMap::new(
+            img.width() as usize,
+            img.height() as usize,
+            pixels,
+        ))
+    }
+
+    pub fn new(width: usize, height: usize, pixels: Vec<Vect>) -> EnvironmentMap {
+        // Rows near the poles cover less solid angle, weigh them by sin(theta)
+        // so we don't waste samples on them.
+        let mut func = Vec::with_capacity(width * height);
+        for row in 0..height {
+            let sin_theta = (PI * (row as f64 + 0.5) / height as f64).sin();
+            for col in 0..width {
+                func.push(pixels[row * width + col].luminance() * sin_theta);
+            }
+        }
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            distribution: Distribution2D::new(&func, width),
+        }
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, dir: &Vect) -> Vect {
+        let (u, v) = dir_to_uv(dir);
+        let col = ((u * self.width as f64) as usize).min(self.width - 1);
+        let row = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[row * self.width + col]
+    }
+
+    fn sample(&self, _normal: &Vect, u1: f64, u2: f64) -> (Vect, f64) {
+        let ((u, v), pdf) = self.distribution.sample(u1, u2);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0f64 {
+            return (uv_to_dir(u, v), 0f64);
+        }
+        (uv_to_dir(u, v), pdf / (2f64 * PI * PI * sin_theta))
+    }
+}
+
+#[test]
+fn environment_map_test() {
+    // A single bright pixel should attract (almost) all samples
+    let mut pixels = vec![zero(); 8 * 4];
+    pixels[8 + 5] = Vect(100.0, 100.0, 100.0);
+    let env = EnvironmentMap::new(8, 4, pixels);
+    for i in 0..10 {
+        let (dir, pdf) = env.sample(&Vect(0.0, 1.0, 0.0), i as f64 / 10.0, 0.5);
+        assert!(pdf > 0f64);
+        assert_eq!(env.radiance(&dir), Vect(100.0, 100.0, 100.0));
+    }
+    let (u, v) = dir_to_uv(&uv_to_dir(0.3, 0.7));
+    assert!((u - 0.3).abs() < 1e-12 && (v - 0.7).abs() < 1e-12);
+}
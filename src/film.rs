@@ -0,0 +1,399 @@
+//! The image being rendered, kept as running sums of the samples taken of
+//! each pixel so that more samples can be added to it at any time.
+
+use crate::ray::PathSample;
+use crate::settings::AdaptiveSettings;
+use crate::vect::*;
+use image::{ImageBuffer, ImageError, ImageFormat, Luma, Rgb, Rgb32FImage, RgbImage};
+
+const GAMMA: f64 = 0.45;
+
+#[derive(Clone, Copy)]
+pub struct Pixel {
+    pub sum: Vect,
+    /// Sum of the squared brightness of the samples, for the variance
+    pub sum_sq: f64,
+    pub samples: u32,
+}
+
+impl Pixel {
+    pub fn new() -> Pixel {
+        Pixel {
+            sum: zero(),
+            sum_sq: 0f64,
+            samples: 0,
+        }
+    }
+
+    pub fn add_sample(&mut self, colour: &Vect) {
+        self.sum = self.sum.add(colour);
+        self.sum_sq += colour.luminance() * colour.luminance();
+        self.samples += 1;
+    }
+
+    /// Average of the samples, black if there are none
+    pub fn mean(&self) -> Vect {
+        if self.samples == 0 {
+            return zero();
+        }
+        self.sum.scalar_mul(&(1f64 / self.samples as f64))
+    }
+
+    /// Whether adaptive sampling is done with the pixel
+    pub fn converged(&self, adaptive: &Option<AdaptiveSettings>) -> bool {
+        match adaptive {
+            None => false,
+            Some(adaptive) => {
+                self.samples >= adaptive.min_rays && self.relative_error() < adaptive.max_error
+            }
+        }
+    }
+
+    /// Standard error of the brightness relative to the brightness itself
+    pub fn relative_error(&self) -> f64 {
+        relative_error(self.sum.luminance(), self.sum_sq, self.samples)
+    }
+}
+
+/// Running sums of the arbitrary output variables of a pixel, the extra
+/// images that come with the colour. Averaged over the samples, or over the
+/// ones that hit anything for the depth and the normal.
+#[derive(Clone, Copy)]
+pub struct AovPixel {
+    pub samples: u32,
+    pub hits: u32,
+    /// Camera space depth
+    pub depth: f64,
+    pub normal: Vect,
+    pub albedo: Vect,
+    pub direct: Vect,
+    pub indirect: Vect,
+    /// The object the first sample to hit anything hit
+    pub object: Option<u32>,
+}
+
+impl AovPixel {
+    pub fn new() -> AovPixel {
+        AovPixel {
+            samples: 0,
+            hits: 0,
+            depth: 0f64,
+            normal: zero(),
+            albedo: zero(),
+            direct: zero(),
+            indirect: zero(),
+            object: None,
+        }
+    }
+
+    /// Add a sample, whose first hit is depth away from the camera
+    pub fn add_sample(&mut self, path: &PathSample, depth: f64) {
+        self.samples += 1;
+        self.direct = self.direct.add(&path.direct);
+        self.indirect = self.indirect.add(&path.colour.sub(&path.direct));
+        if let Some(hit) = &path.first_hit {
+            self.hits += 1;
+            self.depth += depth;
+            self.normal = self.normal.add(&hit.normal);
+            self.albedo = self.albedo.add(&hit.albedo);
+            self.object = self.object.or(Some(hit.object as u32));
+        }
+    }
+
+    /// Average of one of the layers, with infinite depth and a zero normal
+    /// where nothing was hit
+    pub fn layer(&self, layer: AovLayer) -> Vect {
+        let over = |n: u32| if n == 0 { 0f64 } else { 1f64 / n as f64 };
+        match layer {
+            AovLayer::Depth => {
+                let depth = if self.hits == 0 {
+                    f64::INFINITY
+                } else {
+                    self.depth / self.hits as f64
+                };
+                Vect(depth, depth, depth)
+            }
+            AovLayer::Normal => self.normal.scalar_mul(&over(self.hits)),
+            AovLayer::Albedo => self.albedo.scalar_mul(&over(self.samples)),
+            AovLayer::Direct => self.direct.scalar_mul(&over(self.samples)),
+            AovLayer::Indirect => self.indirect.scalar_mul(&over(self.samples)),
+        }
+    }
+}
+
+/// The AOVs averaged over the samples of a pixel, which get saved as EXR
+/// images
+#[derive(Clone, Copy)]
+pub enum AovLayer {
+    Depth,
+    Normal,
+    Albedo,
+    Direct,
+    Indirect,
+}
+
+impl AovLayer {
+    /// What goes after the prefix in the name of the layer's image
+    fn name(&self) -> &'static str {
+        match self {
+            AovLayer::Depth => "depth",
+            AovLayer::Normal => "normal",
+            AovLayer::Albedo => "albedo",
+            AovLayer::Direct => "direct",
+            AovLayer::Indirect => "indirect",
+        }
+    }
+}
+
+/// Radiance to the 0 to 255 range of the output image
+pub fn tonemap(colour: &Vect) -> Vect {
+    let Vect(r, g, b) = *colour;
+    let [r, g, b] = [r, g, b].map(|c| c.max(0f64).powf(GAMMA).min(255f64));
+    Vect(r, g, b)
+}
+
+/// The radiance that tonemap turns into colour, for showing colours in the
+/// 0 to 255 range as they are
+pub fn untonemap(colour: &Vect) -> Vect {
+    let Vect(r, g, b) = *colour;
+    let [r, g, b] = [r, g, b].map(|c| c.max(0f64).powf(1f64 / GAMMA));
+    Vect(r, g, b)
+}
+
+/// Gamma corrected image of the given radiances, stored row by row
+pub fn to_image(width: u32, height: u32, colours: &[Vect]) -> RgbImage {
+    let mut res = RgbImage::new(width, height);
+    for (i, colour) in colours.iter().enumerate() {
+        let Vect(r, g, b) = tonemap(colour);
+        res.put_pixel(
+            i as u32 % width,
+            i as u32 / width,
+            Rgb([r as u8, g as u8, b as u8]),
+        );
+    }
+    res
+}
+
+const AOV_LAYERS: [AovLayer; 5] = [
+    AovLayer::Depth,
+    AovLayer::Normal,
+    AovLayer::Albedo,
+    AovLayer::Direct,
+    AovLayer::Indirect,
+];
+
+/// Standard error of the mean of n samples relative to the mean, given the
+/// sum of the samples and the sum of their squares. Zero for a pixel that
+/// is black in every sample.
+fn relative_error(sum: f64, sum_sq: f64, n: u32) -> f64 {
+    let n = n as f64;
+    let mean = sum / n;
+    let variance = ((sum_sq - sum * sum / n) / (n - 1f64)).max(0f64);
+    if variance == 0f64 {
+        return 0f64;
+    }
+    if mean <= 0f64 {
+        return f64::INFINITY;
+    }
+    (variance / n).sqrt() / mean
+}
+
+/// Pixels stored row by row
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Pixel>,
+    /// Only kept track of when asked for
+    pub aovs: Option<Vec<AovPixel>>,
+    /// Sums of the light traced onto each pixel from paths started at the
+    /// lights, for integrators that do that. One light path is traced per
+    /// sample taken.
+    pub splats: Option<Vec<Vect>>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![Pixel::new(); (width * height) as usize],
+            aovs: None,
+            splats: None,
+        }
+    }
+
+    /// Start keeping track of the AOVs, if not already
+    pub fn enable_aovs(&mut self) {
+        if self.aovs.is_none() {
+            self.aovs = Some(vec![AovPixel::new(); self.pixels.len()]);
+        }
+    }
+
+    /// Start keeping track of light traced from the lights, if not already
+    pub fn enable_splats(&mut self) {
+        if self.splats.is_none() {
+            self.splats = Some(vec![zero(); self.pixels.len()]);
+        }
+    }
+
+    /// Add light to the pixels at the given (row, col), leaving out any
+    /// that fall outside the film
+    pub fn add_splats(&mut self, splats: &[((u32, u32), Vect)]) {
+        if let Some(film_splats) = &mut self.splats {
+            for ((row, col), colour) in splats {
+                if *row < self.height && *col < self.width {
+                    let i = (row * self.width + col) as usize;
+                    film_splats[i] = film_splats[i].add(colour);
+                }
+            }
+        }
+    }
+
+    pub fn row(&self, row: u32) -> &[Pixel] {
+        let start = (row * self.width) as usize;
+        &self.pixels[start..start + self.width as usize]
+    }
+
+    pub fn aov_row(&self, row: u32) -> Option<&[AovPixel]> {
+        let start = (row * self.width) as usize;
+        self.aovs
+            .as_ref()
+            .map(|aovs| &aovs[start..start + self.width as usize])
+    }
+
+    pub fn set_row(&mut self, row: u32, pixels: &[Pixel], aovs: Option<&[AovPixel]>) {
+        let start = (row * self.width) as usize;
+        let end = start + self.width as usize;
+        self.pixels[start..end].copy_from_slice(pixels);
+        if let (Some(film_aovs), Some(aovs)) = (&mut self.aovs, aovs) {
+            film_aovs[start..end].copy_from_slice(aovs);
+        }
+    }
+
+    /// Average relative error of the pixels, infinite until they all have
+    /// at least two samples.
+    pub fn noise(&self) -> f64 {
+        let mut tot = 0f64;
+        for pixel in &self.pixels {
+            if pixel.samples < 2 {
+                return f64::INFINITY;
+            }
+            tot += pixel.relative_error();
+        }
+        tot / self.pixels.len() as f64
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.samples as u64).sum()
+    }
+
+    /// The image so far, gamma corrected
+    pub fn image(&self) -> RgbImage {
+        to_image(self.width, self.height, &self.colours())
+    }
+
+    /// Radiance of every pixel: the average of its samples, plus the light
+    /// traced onto it averaged over all the light paths
+    pub fn colours(&self) -> Vec<Vect> {
+        let means = self.pixels.iter().map(|p| p.mean());
+        match &self.splats {
+            None => means.collect(),
+            Some(splats) => {
+                // Light paths spread over the camera's whole image, of which
+                // the film may only be a corner
+                let light_paths_per_pixel =
+                    self.total_samples() as f64 / (crate::IMAGE_WIDTH * crate::IMAGE_HEIGTH) as f64;
+                means
+                    .zip(splats)
+                    .map(|(mean, splat)| {
+                        if light_paths_per_pixel == 0f64 {
+                            return mean;
+                        }
+                        mean.add(&splat.scalar_mul(&(1f64 / light_paths_per_pixel)))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Visualise the number of samples per pixel, from blue for none to red
+    /// for the most any pixel got.
+    pub fn heatmap(&self) -> RgbImage {
+        let max_samples = self.pixels.iter().map(|p| p.samples).max().unwrap_or(0);
+        let mut res = RgbImage::new(self.width, self.height);
+        for (i, pixel) in self.pixels.iter().enumerate() {
+            let t = pixel.samples as f64 / max_samples.max(1) as f64;
+            res.put_pixel(
+                i as u32 % self.width,
+                i as u32 / self.width,
+                Rgb([(255f64 * t) as u8, 0, (255f64 * (1f64 - t)) as u8]),
+            );
+        }
+        res
+    }
+
+    /// Save each AOV the film has kept track of as a separate image, named
+    /// prefix_<aov>. Object IDs go in a 16 bit greyscale PNG, one more than
+    /// the index of the object and zero where nothing was hit. Everything
+    /// else is stored as is in an EXR, with infinite depth where nothing was
+    /// hit.
+    pub fn save_aovs(&self, prefix: &str) -> Result<(), ImageError> {
+        let aovs = match &self.aovs {
+            Some(aovs) => aovs,
+            None => return Ok(()),
+        };
+        for layer in AOV_LAYERS {
+            let mut image = Rgb32FImage::new(self.width, self.height);
+            for (i, pixel) in aovs.iter().enumerate() {
+                let Vect(r, g, b) = pixel.layer(layer);
+                image.put_pixel(
+                    i as u32 % self.width,
+                    i as u32 / self.width,
+                    Rgb([r as f32, g as f32, b as f32]),
+                );
+            }
+            image::DynamicImage::ImageRgb32F(image).save_with_format(
+                format!("{}_{}.exr", prefix, layer.name()),
+                ImageFormat::OpenExr,
+            )?;
+        }
+        let mut ids: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::new(self.width, self.height);
+        for (i, pixel) in aovs.iter().enumerate() {
+            let id = pixel
+                .object
+                .map_or(0, |id| (id + 1).min(u16::MAX as u32) as u16);
+            ids.put_pixel(i as u32 % self.width, i as u32 / self.width, Luma([id]));
+        }
+        ids.save_with_format(format!("{}_id.png", prefix), ImageFormat::Png)
+    }
+}
+
+#[test]
+fn pixel_test() {
+    assert_eq!(relative_error(0f64, 0f64, 4), 0f64);
+    assert_eq!(relative_error(8f64, 16f64, 4), 0f64);
+    // Samples 1, 3: mean 2, variance 2, standard error 1
+    assert!((relative_error(4f64, 10f64, 2) - 0.5).abs() < 1e-12);
+
+    let mut pixel = Pixel::new();
+    assert_eq!(pixel.mean(), zero());
+    pixel.add_sample(&Vect(1.0, 1.0, 1.0));
+    pixel.add_sample(&Vect(3.0, 3.0, 3.0));
+    assert_eq!(pixel.mean(), Vect(2.0, 2.0, 2.0));
+    let adaptive = |max_error| {
+        Some(AdaptiveSettings {
+            min_rays: 2,
+            max_error,
+        })
+    };
+    assert!(pixel.converged(&adaptive(0.6)));
+    assert!(!pixel.converged(&adaptive(0.4)));
+    assert!(!pixel.converged(&None));
+
+    let mut film = Film::new(2, 1);
+    film.pixels[0] = pixel;
+    assert_eq!(film.noise(), f64::INFINITY);
+    film.pixels[1].add_sample(&Vect(1.0, 1.0, 1.0));
+    film.pixels[1].add_sample(&Vect(1.0, 1.0, 1.0));
+    assert!((film.noise() - 0.25).abs() < 1e-12);
+}
@@ -1,7 +1,19 @@
+use crate::medium::Medium;
 use crate::ray::Ray;
+use crate::texture::SurfaceMap;
 use crate::typedefs::*;
 
 pub trait Geometry {
     fn intersect(&self, ray: &Ray) -> Intersection;
     fn get_material(&self) -> Material;
+
+    /// The medium filling the inside of closed objects, if any
+    fn get_medium(&self) -> Option<&Medium> {
+        None
+    }
+
+    /// Normal or bump map tilting the normal for shading, if any
+    fn get_surface_map(&self) -> Option<&SurfaceMap> {
+        None
+    }
 }
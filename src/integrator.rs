@@ -0,0 +1,505 @@
+//! The different ways of working out the light arriving along a camera ray.
+//! Path tracing, in ray.rs, is the default. Bidirectional path tracing in
+//! bdpt.rs copes better with light that is hard to find from the camera,
+//! and photon mapping in photon.rs adds the caustics path tracing misses.
+//! Whitted ray tracing gives noise-free previews, the debug integrator
+//! shows things about the scene that are hard to see in a normal render,
+//! and ambient occlusion gives a quick clay render of the geometry.
+
+use crate::bdpt;
+use crate::bsdf::{fresnel_dielectric, reflect, refract};
+use crate::camera::Camera;
+use crate::film::{tonemap, untonemap};
+use crate::ray::{direct_light, FirstHit, PathSample, Ray};
+use crate::sampler::Sampler;
+use crate::sampling::{local_to_world, uniform_hemisphere_pdf, uniform_sample_hemisphere};
+use crate::settings::{DebugMode, Integrator, RenderSettings};
+use crate::stats::RenderStats;
+use crate::typedefs::Material;
+use crate::typedefs::Scene;
+use crate::vect::*;
+
+/// Surfaces that get less direct light than this count as unlit
+const UNLIT_THRESHOLD: f64 = 0.1;
+const MAGENTA: Vect = Vect(255.0, 0.0, 250.0);
+const CYAN: Vect = Vect(0.0, 255.0, 255.0);
+/// Surfaces Whitted rays go through at most. Glass splits every ray in
+/// two, so this stays well short of the depth paths are allowed.
+const WHITTED_DEPTH: u8 = 10;
+
+impl Integrator {
+    /// Light arriving at the origin of the camera ray from its direction,
+    /// and what the ray hit first
+    pub fn trace(
+        &self,
+        ray: &Ray,
+        cam: &Camera,
+        scene: &Scene,
+        settings: &RenderSettings,
+        sampler: &mut dyn Sampler,
+        stats: &mut RenderStats,
+    ) -> PathSample {
+        match self {
+            Integrator::Path | Integrator::PhotonMapping { .. } => {
+                ray.trace(scene, settings, sampler, stats)
+            }
+            Integrator::Bidirectional => bdpt::trace(ray, cam, scene, settings, sampler, stats),
+            Integrator::Whitted => {
+                stats.camera_rays += 1;
+                whitted(
+                    ray,
+                    scene,
+                    settings.depth.min(WHITTED_DEPTH),
+                    sampler,
+                    stats,
+                )
+            }
+            Integrator::Debug(mode) => debug(ray, *mode, scene, settings, sampler, stats),
+            Integrator::AmbientOcclusion { rays, max_distance } => {
+                ambient_occlusion(ray, *rays, *max_distance, scene, sampler, stats)
+            }
+        }
+    }
+}
+
+/// A sample whose colour shows up in the image as colour, in 0 to 255
+fn shown(colour: &Vect, first_hit: Option<FirstHit>) -> PathSample {
+    let colour = untonemap(colour);
+    PathSample {
+        colour,
+        direct: colour,
+        first_hit,
+        splats: Vec::new(),
+    }
+}
+
+fn debug(
+    ray: &Ray,
+    mode: DebugMode,
+    scene: &Scene,
+    settings: &RenderSettings,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> PathSample {
+    if mode == DebugMode::NanInf {
+        let path = ray.trace(scene, settings, sampler, stats);
+        let Vect(r, g, b) = path.colour;
+        let colour = if r.is_nan() || g.is_nan() || b.is_nan() {
+            MAGENTA
+        } else if r.is_infinite() || g.is_infinite() || b.is_infinite() {
+            CYAN
+        } else {
+            let grey = tonemap(&path.colour).luminance();
+            Vect(grey, grey, grey)
+        };
+        return shown(&colour, path.first_hit);
+    }
+    let (intersection, material, object) = match ray.closest_hit(scene) {
+        Some(hit) => hit,
+        None => {
+            stats.camera_rays += 1;
+            stats.rays += 1;
+            return shown(&zero(), None);
+        }
+    };
+    let normal = intersection.shading_normal;
+    let first_hit = Some(FirstHit {
+        pos: intersection.pos,
+        normal,
+        albedo: material.albedo(),
+        object,
+    });
+    let colour = match mode {
+        DebugMode::Unlit => {
+            let wo = ray.1.scalar_mul(&-1f64);
+            if !material.is_specular() {
+                let (_, tot_light) = direct_light(
+                    &intersection,
+                    &material,
+                    &wo,
+                    scene.3.as_ref(),
+                    scene,
+                    sampler,
+                    stats,
+                );
+                if tot_light <= UNLIT_THRESHOLD {
+                    stats.camera_rays += 1;
+                    stats.rays += 1;
+                    return shown(&MAGENTA, first_hit);
+                }
+            }
+            return ray.trace(scene, settings, sampler, stats);
+        }
+        DebugMode::Normals => normal
+            .add(&Vect(1.0, 1.0, 1.0))
+            .scalar_mul(&(255f64 / 2f64)),
+        DebugMode::Uv => {
+            let (u, v) = intersection.uv;
+            Vect(
+                255f64 * u.rem_euclid(1f64),
+                255f64 * v.rem_euclid(1f64),
+                0f64,
+            )
+        }
+        DebugMode::FacingRatio => {
+            let grey = 255f64 * normal.dot(&ray.1).abs();
+            Vect(grey, grey, grey)
+        }
+        DebugMode::Depth => {
+            let grey = 255f64 / (1f64 + intersection.pos.sub(&ray.0).norm() / 10f64);
+            Vect(grey, grey, grey)
+        }
+        DebugMode::NanInf => unreachable!(),
+    };
+    stats.camera_rays += 1;
+    stats.rays += 1;
+    shown(&colour, first_hit)
+}
+
+/// Light arriving along the ray, following mirror reflection and
+/// refraction for up to bounces surfaces. Other surfaces are lit straight
+/// from the lights, with lights that aren't points shrunk to a point, and
+/// the environment is only seen by rays that leave the scene. Participating
+/// media are left out.
+fn whitted(
+    ray: &Ray,
+    scene: &Scene,
+    bounces: u8,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> PathSample {
+    let mut res = PathSample {
+        colour: zero(),
+        direct: zero(),
+        first_hit: None,
+        splats: Vec::new(),
+    };
+    if bounces == 0 {
+        return res;
+    }
+    stats.rays += 1;
+    let (intersection, material, object) = match ray.closest_hit(scene) {
+        Some(hit) => hit,
+        None => {
+            res.colour = scene.2.radiance(&ray.1);
+            res.direct = res.colour;
+            return res;
+        }
+    };
+    let normal = intersection.shading_normal;
+    let wo = ray.1.scalar_mul(&-1f64);
+    let follow = |dir: Vect, sampler: &mut dyn Sampler, stats: &mut RenderStats| {
+        whitted(
+            &Ray::leaving(&intersection, dir),
+            scene,
+            bounces - 1,
+            sampler,
+            stats,
+        )
+        .colour
+    };
+    res.colour = match material {
+        Material::Mirror => follow(reflect(&wo, &normal), sampler, stats),
+        Material::Interface => follow(ray.1, sampler, stats),
+        Material::Glass(ior) => {
+            let reflected = follow(reflect(&wo, &normal), sampler, stats);
+            match refract(&wo, &normal, ior) {
+                None => reflected,
+                Some(dir) => {
+                    let reflectance = fresnel_dielectric(normal.dot(&wo), ior);
+                    reflected
+                        .scalar_mul(&reflectance)
+                        .add(&follow(dir, sampler, stats).scalar_mul(&(1f64 - reflectance)))
+                }
+            }
+        }
+        Material::Lambertian(_)
+        | Material::Phong(..)
+        | Material::Subsurface(..)
+        | Material::Principled(_) => {
+            let mut colour = material.emission();
+            for light in &scene.1 {
+                // Zero picks the centre of lights with a size
+                if let Some(sample) =
+                    light.get_contribution(&intersection, scene, None, sampler, stats, 0f64, 0f64)
+                {
+                    colour = colour.add(
+                        &material
+                            .eval(&normal, &wo, &sample.dir)
+                            .scalar_mul(&sample.strength),
+                    );
+                }
+            }
+            colour
+        }
+    };
+    res.direct = res.colour;
+    res.first_hit = Some(FirstHit {
+        pos: intersection.pos,
+        normal,
+        albedo: material.albedo(),
+        object,
+    });
+    res
+}
+
+/// Fraction of rays sent off uniformly over the hemisphere of the first
+/// hit that get further than max_distance, in grey. Anything that misses
+/// the scene is fully unoccluded.
+fn ambient_occlusion(
+    ray: &Ray,
+    rays: u32,
+    max_distance: f64,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> PathSample {
+    stats.camera_rays += 1;
+    stats.rays += 1;
+    let (intersection, material, object) = match ray.closest_hit(scene) {
+        Some(hit) => hit,
+        None => return shown(&Vect(255.0, 255.0, 255.0), None),
+    };
+    // Occlusion is measured on the side of the surface the camera sees
+    let normal = if intersection.normal.dot(&ray.1) > 0f64 {
+        intersection.normal.scalar_mul(&-1f64)
+    } else {
+        intersection.normal
+    };
+    // Estimate of the solid angle the rays get out through, over that of
+    // the whole hemisphere
+    let mut unoccluded = 0f64;
+    for _ in 0..rays {
+        let (u1, u2) = sampler.get_2d();
+        let dir = local_to_world(&uniform_sample_hemisphere(u1, u2), &normal);
+        if !Ray::leaving(&intersection, dir).occluded(scene, max_distance, stats) {
+            unoccluded += 1f64 / uniform_hemisphere_pdf();
+        }
+    }
+    let grey = 255f64 * unoccluded / (rays as f64 * 2f64 * std::f64::consts::PI);
+    shown(
+        &Vect(grey, grey, grey),
+        Some(FirstHit {
+            pos: intersection.pos,
+            normal: intersection.shading_normal,
+            albedo: material.albedo(),
+            object,
+        }),
+    )
+}
+
+/// A camera at the origin looking down the z axis
+#[cfg(test)]
+fn test_camera() -> Camera {
+    crate::camera::new(
+        zero(),
+        Vect(0.0, 0.0, 1.0),
+        Vect(0.0, 1.0, 0.0),
+        std::f64::consts::PI / 3f64,
+    )
+}
+
+#[test]
+fn debug_test() {
+    use crate::environment::Constant;
+    use crate::sampler;
+    use crate::sphere::Sphere;
+    use crate::typedefs::Material;
+    let scene: Scene = (
+        vec![Box::new(Sphere {
+            pos: Vect(0.0, 0.0, 5.0),
+            radius: 1f64,
+            material: Material::Lambertian(Vect(0.5, 0.5, 0.5)),
+            medium: None,
+            surface_map: None,
+        })],
+        vec![],
+        Box::new(Constant(zero())),
+        None,
+    );
+    let settings = RenderSettings::default();
+    let mut sampler = sampler::new(settings.sampler, settings.seed, 1);
+    let mut stats = RenderStats::default();
+    let ray = Ray(zero(), Vect(0.0, 0.0, 1.0));
+    let mut show = |mode| {
+        sampler.start_sample(0, 0, 0);
+        tonemap(
+            &Integrator::Debug(mode)
+                .trace(
+                    &ray,
+                    &test_camera(),
+                    &scene,
+                    &settings,
+                    &mut *sampler,
+                    &mut stats,
+                )
+                .colour,
+        )
+    };
+    // Head on to a sphere lit by nothing
+    let Vect(r, g, b) = show(DebugMode::Unlit);
+    assert!((r - 255f64).abs() < 1e-6 && g.abs() < 1e-6 && (b - 250f64).abs() < 1e-6);
+    assert!((show(DebugMode::FacingRatio).0 - 255f64).abs() < 1e-6);
+    let Vect(_, _, b) = show(DebugMode::Normals);
+    assert!(b.abs() < 1e-6);
+    assert!(show(DebugMode::Depth).0 < 255f64);
+}
+
+#[test]
+fn ambient_occlusion_test() {
+    use crate::environment::Constant;
+    use crate::plane::Plane;
+    use crate::sampler;
+    use crate::sphere::Sphere;
+    use crate::typedefs::Material;
+    // A floor with a sphere resting on it, seen from above
+    let floor = || -> Box<dyn crate::geometry::Geometry + Send + Sync> {
+        Box::new(Plane {
+            point: zero(),
+            normal: Vect(0.0, 1.0, 0.0),
+            material: Material::Lambertian(Vect(0.5, 0.5, 0.5)),
+            surface_map: None,
+        })
+    };
+    let sphere = Box::new(Sphere {
+        pos: Vect(0.0, 1.0, 0.0),
+        radius: 1f64,
+        material: Material::Lambertian(Vect(0.5, 0.5, 0.5)),
+        medium: None,
+        surface_map: None,
+    });
+    let settings = RenderSettings::default();
+    let mut sampler = sampler::new(settings.sampler, settings.seed, 1);
+    let mut stats = RenderStats::default();
+    let mut ao = |scene: &Scene, pos: Vect, max_distance| {
+        sampler.start_sample(0, 0, 0);
+        let ray = Ray(pos.add(&Vect(0.0, 10.0, 0.0)), Vect(0.0, -1.0, 0.0));
+        tonemap(
+            &Integrator::AmbientOcclusion {
+                rays: 256,
+                max_distance,
+            }
+            .trace(
+                &ray,
+                &test_camera(),
+                scene,
+                &settings,
+                &mut *sampler,
+                &mut stats,
+            )
+            .colour,
+        )
+        .0 / 255f64
+    };
+    let open: Scene = (vec![floor()], vec![], Box::new(Constant(zero())), None);
+    assert!((ao(&open, zero(), f64::INFINITY) - 1f64).abs() < 1e-6);
+    let scene: Scene = (
+        vec![floor(), sphere],
+        vec![],
+        Box::new(Constant(zero())),
+        None,
+    );
+    // Right next to the sphere about a quarter of the hemisphere is covered
+    let near = ao(&scene, Vect(1.05, 0.0, 0.0), f64::INFINITY);
+    assert!(near > 0.5 && near < 0.9);
+    // but the sphere is out of reach of short rays
+    assert!(ao(&scene, Vect(1.05, 0.0, 0.0), 0.01) > 0.9);
+    assert!(ao(&scene, Vect(10.0, 0.0, 0.0), f64::INFINITY) > 0.95);
+}
+
+#[test]
+fn whitted_test() {
+    use crate::environment::Constant;
+    use crate::light::Pointlight;
+    use crate::sampler;
+    use crate::sphere::Sphere;
+    use std::f64::consts::PI;
+    let scene = |material| -> Scene {
+        (
+            vec![Box::new(Sphere {
+                pos: Vect(0.0, 0.0, 5.0),
+                radius: 1f64,
+                material,
+                medium: None,
+                surface_map: None,
+            })],
+            // Irradiance of one on the near side of the sphere
+            vec![Box::new(Pointlight {
+                pos: Vect(0.0, 0.0, 0.0),
+                intensity: 4f64 * PI * 16f64,
+            })],
+            Box::new(Constant(Vect(1.0, 1.0, 1.0))),
+            None,
+        )
+    };
+    let settings = RenderSettings::default();
+    let mut sampler = sampler::new(settings.sampler, settings.seed, 1);
+    let mut stats = RenderStats::default();
+    let ray = Ray(zero(), Vect(0.0, 0.0, 1.0));
+    let mut colour = |material| {
+        Integrator::Whitted
+            .trace(
+                &ray,
+                &test_camera(),
+                &scene(material),
+                &settings,
+                &mut *sampler,
+                &mut stats,
+            )
+            .colour
+    };
+    let lambertian = colour(Material::Lambertian(Vect(0.5, 0.5, 0.5)));
+    // The surface point is nudged a little closer to the light
+    assert!((lambertian.0 / (0.5 / PI) - 1f64).abs() < 1e-3);
+    assert_eq!(
+        lambertian,
+        colour(Material::Lambertian(Vect(0.5, 0.5, 0.5)))
+    );
+    assert!((colour(Material::Mirror).0 - 1f64).abs() < 1e-9);
+    // What isn't reflected off the glass goes through it, apart from what
+    // is still bouncing around inside when the depth runs out
+    assert!((colour(Material::Glass(1.5)).0 - 1f64).abs() < 1e-3);
+}
+
+#[test]
+fn whitted_nested_glass_test() {
+    // Rays split at every glass surface, so nested glass balls would take
+    // forever at the full path depth
+    use crate::environment::Constant;
+    use crate::sampler;
+    use crate::sphere::Sphere;
+    let ball = |radius| -> Box<dyn crate::geometry::Geometry + Send + Sync> {
+        Box::new(Sphere {
+            pos: Vect(0.0, 0.0, 5.0),
+            radius,
+            material: Material::Glass(1.5),
+            medium: None,
+            surface_map: None,
+        })
+    };
+    let scene: Scene = (
+        vec![ball(2f64), ball(1f64), ball(0.5)],
+        vec![],
+        Box::new(Constant(Vect(1.0, 1.0, 1.0))),
+        None,
+    );
+    let settings = RenderSettings {
+        integrator: Integrator::Whitted,
+        ..RenderSettings::default()
+    };
+    let mut sampler = sampler::new(settings.sampler, settings.seed, 1);
+    let mut stats = RenderStats::default();
+    let colour = Integrator::Whitted
+        .trace(
+            &Ray(zero(), Vect(0.0, 0.1, 1.0).normalise()),
+            &test_camera(),
+            &scene,
+            &settings,
+            &mut *sampler,
+            &mut stats,
+        )
+        .colour;
+    assert!(stats.rays < 1 << WHITTED_DEPTH);
+    // Most of the light gets through the six surfaces within the limit
+    assert!(colour.0 > 0.9 && colour.0 < 1f64);
+}
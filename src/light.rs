@@ -1,38 +1,251 @@
+use crate::medium::Medium;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::{local_to_world, uniform_sample_cone, uniform_sample_sphere};
+use crate::stats::RenderStats;
 use crate::typedefs::{Intersection, Scene};
 use crate::vect::*;
 use std::f64::consts::PI;
 
-const PI_SQ: f64 = PI * PI;
-
 pub struct Pointlight {
     pub pos: Vect,
     pub intensity: f64,
 }
 
+/// A distant light that covers a small disk of the sky, like the sun.
+/// to_sun points from the scene towards the sun, the opposite way to a
+/// DirectionalLight's dir.
+pub struct Sunlight {
+    pub to_sun: Vect,
+    pub intensity: f64,
+    pub angular_radius: f64,
+}
+
+/// An infinitely distant light whose rays are all parallel, travelling in
+/// direction dir. Intensity doesn't fall off with distance.
+pub struct DirectionalLight {
+    pub dir: Vect,
+    pub intensity: f64,
+}
+
+/// A point light that only shines within a cone around dir. Full intensity
+/// within inner_angle of dir, fading smoothly to nothing at outer_angle.
+/// Angles are in radians, measured from the cone axis.
+pub struct SpotLight {
+    pub pos: Vect,
+    pub dir: Vect,
+    pub intensity: f64,
+    pub inner_angle: f64,
+    pub outer_angle: f64,
+}
+
+/// How light from a light source reaches a point in the scene
+pub struct LightSample {
+    /// Unit vector from the point towards the light
+    pub dir: Vect,
+    /// Distance to the light, infinite for distant lights
+    pub dist: f64,
+    /// Irradiance on a surface facing the light head on
+    pub strength: f64,
+}
+
+/// Light leaving a light with a position, for following it into the scene
+pub struct Emission {
+    /// Unit vector the light leaves in
+    pub dir: Vect,
+    /// Radiant intensity in dir, irradiance times distance squared
+    pub intensity: f64,
+    /// Density of dir with respect to solid angle
+    pub pdf: f64,
+}
+
 pub trait Light {
-    fn get_contribution(&self, intersection: &Intersection, scene: &Scene) -> f64;
+    /// Lights that aren't points use the uniform numbers u1 and u2 to pick
+    /// the point the light comes from.
+    fn sample(&self, pos: &Vect, u1: f64, u2: f64) -> LightSample;
+
+    /// Where the light is, None for distant lights. Only lights with a
+    /// position can be traced from.
+    fn position(&self) -> Option<Vect> {
+        None
+    }
+
+    /// A random direction for light to leave a light with a position in
+    fn emit(&self, _u1: f64, _u2: f64) -> Option<Emission> {
+        None
+    }
+
+    /// Density with which emit() picks dir
+    fn emit_pdf(&self, _dir: &Vect) -> f64 {
+        0f64
+    }
+
+    /// Light reaching the intersection from this light through medium, with
+    /// the cosine factor and the transmittance included in its strength.
+    /// The sampler is for estimating transmittance through media that vary
+    /// in density. The shadow rays it takes, if any, are counted in stats.
+    /// None if the light is blocked or behind the surface.
+    #[allow(clippy::too_many_arguments)]
+    fn get_contribution(
+        &self,
+        intersection: &Intersection,
+        scene: &Scene,
+        medium: Option<&Medium>,
+        sampler: &mut dyn Sampler,
+        stats: &mut RenderStats,
+        u1: f64,
+        u2: f64,
+    ) -> Option<LightSample> {
+        let shifted_pos = intersection
+            .pos
+            .add(&intersection.normal.scalar_mul(&crate::EPSILON));
+        let sample = self.sample(&shifted_pos, u1, u2);
+        let angle_contribution = intersection.shading_normal.dot(&sample.dir);
+        if angle_contribution <= 0f64
+            || intersection.normal.dot(&sample.dir) <= 0f64
+            || sample.strength <= 0f64
+        {
+            return None;
+        }
+        let ip_to_light = Ray(shifted_pos, sample.dir);
+        let transmittance = ip_to_light.transmittance(scene, sample.dist, medium, sampler, stats);
+        if transmittance <= 0f64 {
+            return None;
+        }
+        Some(LightSample {
+            strength: sample.strength * angle_contribution * transmittance,
+            ..sample
+        })
+    }
 }
 
 impl Light for Pointlight {
-    fn get_contribution(&self, intersection: &Intersection, scene: &Scene) -> f64 {
-        let d_vec = self.pos.sub(&intersection.pos);
+    fn sample(&self, pos: &Vect, _u1: f64, _u2: f64) -> LightSample {
+        let d_vec = self.pos.sub(pos);
         let d_squared = d_vec.norm_sq();
         let d = d_squared.sqrt();
-        let d_vec = d_vec.scalar_div(&d).unwrap();
-        let shifted_pos = intersection
-            .pos
-            .add(&intersection.normal.scalar_mul(&crate::EPSILON));
-        let ip_to_light = Ray(shifted_pos, d_vec);
-        for geo in &scene.0 {
-            let block_check = geo.intersect(&ip_to_light);
-            if block_check.normal != zero() {
-                if block_check.pos.sub(&shifted_pos).norm() < d {
-                    return 0f64;
-                }
-            }
-        }
-        let angle_contribution = intersection.normal.dot(&d_vec);
-        (self.intensity * angle_contribution) / (4f64 * PI_SQ * d_squared)
+        LightSample {
+            dir: d_vec.normalise(),
+            dist: d,
+            strength: self.intensity / (4f64 * PI * d_squared),
+        }
     }
+
+    fn position(&self) -> Option<Vect> {
+        Some(self.pos)
+    }
+
+    fn emit(&self, u1: f64, u2: f64) -> Option<Emission> {
+        Some(Emission {
+            dir: uniform_sample_sphere(u1, u2),
+            intensity: self.intensity / (4f64 * PI),
+            pdf: self.emit_pdf(&zero()),
+        })
+    }
+
+    fn emit_pdf(&self, _dir: &Vect) -> f64 {
+        1f64 / (4f64 * PI)
+    }
+}
+
+impl Light for Sunlight {
+    fn sample(&self, _pos: &Vect, u1: f64, u2: f64) -> LightSample {
+        // Pick a point on the sun's disk for soft shadows
+        let local = uniform_sample_cone(u1, u2, self.angular_radius.cos());
+        LightSample {
+            dir: local_to_world(&local, &self.to_sun),
+            dist: f64::INFINITY,
+            strength: self.intensity,
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _pos: &Vect, _u1: f64, _u2: f64) -> LightSample {
+        LightSample {
+            dir: self.dir.scalar_mul(&-1f64).normalise(),
+            dist: f64::INFINITY,
+            strength: self.intensity,
+        }
+    }
+}
+
+impl SpotLight {
+    /// How much of the light goes out in direction dir (pointing away from
+    /// the light), between 0 and 1.
+    fn falloff(&self, dir: &Vect) -> f64 {
+        let cos_angle = dir.dot(&self.dir.normalise());
+        let cos_inner = self.inner_angle.cos();
+        let cos_outer = self.outer_angle.cos();
+        if cos_angle >= cos_inner {
+            return 1f64;
+        }
+        if cos_angle <= cos_outer {
+            return 0f64;
+        }
+        let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+        t * t * (3f64 - 2f64 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, pos: &Vect, _u1: f64, _u2: f64) -> LightSample {
+        let d_vec = self.pos.sub(pos);
+        let d_squared = d_vec.norm_sq();
+        let d = d_squared.sqrt();
+        let dir = d_vec.normalise();
+        LightSample {
+            dir,
+            dist: d,
+            strength: self.falloff(&dir.scalar_mul(&-1f64)) * self.intensity
+                / (4f64 * PI * d_squared),
+        }
+    }
+
+    fn position(&self) -> Option<Vect> {
+        Some(self.pos)
+    }
+
+    fn emit(&self, u1: f64, u2: f64) -> Option<Emission> {
+        // Only within the outer cone, where there's any light at all
+        let local = uniform_sample_cone(u1, u2, self.outer_angle.cos());
+        let dir = local_to_world(&local, &self.dir.normalise());
+        Some(Emission {
+            dir,
+            intensity: self.falloff(&dir) * self.intensity / (4f64 * PI),
+            pdf: self.emit_pdf(&dir),
+        })
+    }
+
+    fn emit_pdf(&self, dir: &Vect) -> f64 {
+        let cos_outer = self.outer_angle.cos();
+        if dir.dot(&self.dir.normalise()) < cos_outer {
+            return 0f64;
+        }
+        1f64 / (2f64 * PI * (1f64 - cos_outer))
+    }
+}
+
+#[test]
+fn spot_light_test() {
+    let spot = SpotLight {
+        pos: Vect(0.0, 10.0, 0.0),
+        dir: Vect(0.0, -1.0, 0.0),
+        intensity: 1f64,
+        inner_angle: PI / 8f64,
+        outer_angle: PI / 4f64,
+    };
+    let point = Pointlight {
+        pos: Vect(0.0, 10.0, 0.0),
+        intensity: 1f64,
+    };
+    // Same as a point light in the middle of the cone, dark outside of it
+    let below = Vect(0.0, 0.0, 0.0);
+    assert_eq!(
+        spot.sample(&below, 0.5, 0.5).strength,
+        point.sample(&below, 0.5, 0.5).strength
+    );
+    assert_eq!(spot.sample(&Vect(20.0, 0.0, 0.0), 0.5, 0.5).strength, 0f64);
+    let edge = spot.sample(&Vect(10.0 * (3f64 * PI / 16f64).tan(), 0.0, 0.0), 0.5, 0.5);
+    assert!(edge.strength > 0f64 && edge.strength < point.sample(&below, 0.5, 0.5).strength);
 }
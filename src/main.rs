@@ -1,16 +1,39 @@
+mod bdpt;
+mod bsdf;
 mod camera;
+mod checkpoint;
+mod denoise;
+mod environment;
+mod film;
 mod geometry;
+mod integrator;
 mod light;
+mod medium;
+mod photon;
 mod plane;
+mod principled;
 mod ray;
+mod sampler;
+mod sampling;
 mod scene_loader;
+mod settings;
+mod sky;
 mod sphere;
+mod stats;
+mod subsurface;
+mod texture;
 mod typedefs;
 mod vect;
+mod volume;
+use camera::{CancelToken, Progress, RenderStatus};
+use film::Film;
 use image::ImageFormat;
 use scene_loader::load_scene;
 use std::f64::consts::PI;
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use vect::*;
 
 //Benchmark 10 rays 4 depth 8 threads
@@ -22,7 +45,7 @@ const EPSILON: f64 = 0.0001;
 const IMAGE_WIDTH: u32 = 1000;
 const IMAGE_HEIGTH: u32 = 1000;
 const NRAYS: u32 = 10;
-const DEPTH: u8 = 8;
+const DEPTH: u8 = 64;
 const N_THREADS: usize = 8;
 
 fn main() {
@@ -32,17 +55,123 @@ fn main() {
         Vect(0f64, 1f64, 0f64), // "Up" Direction
         PI / 3f64,              // Viewing angle
     );
-    let scene = match load_scene("scene.toml") {
+    let (scene, settings) = match load_scene("scene.toml") {
         Ok(s) => s,
         Err(e) => {
             println!("{}", e);
             return;
         }
     };
+    let mut film = match &settings.checkpoint {
+        Some(ckpt) if ckpt.resume && Path::new(&ckpt.filename).exists() => {
+            match checkpoint::load(&ckpt.filename, ckpt.scene_hash, settings.seed) {
+                Ok(film) => {
+                    println!("Resuming from {}", ckpt.filename);
+                    film
+                }
+                Err(e) => {
+                    println!("Couldn't resume from {}: {}", ckpt.filename, e);
+                    return;
+                }
+            }
+        }
+        _ => Film::new(IMAGE_WIDTH, IMAGE_HEIGTH),
+    };
+    // The denoiser needs the AOVs to find edges with
+    if settings.aovs.is_some() || settings.denoise.is_some() {
+        film.enable_aovs();
+    }
+    if settings.integrator == settings::Integrator::Bidirectional {
+        film.enable_splats();
+    }
+    // The first Ctrl-C stops the render and keeps what's done, the second
+    // one gives up on it
+    let cancel = CancelToken::new();
+    let handler_cancel = cancel.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        if handler_cancel.is_cancelled() {
+            std::process::exit(130);
+        }
+        handler_cancel.cancel();
+    }) {
+        println!("Couldn't set the Ctrl-C handler: {}", e);
+    }
     let scene_p = Arc::new(scene);
-    let img = cam.render(scene_p, NRAYS, DEPTH);
-    match img.save_with_format("test_img.png", ImageFormat::Png) {
+    let output = cam.render(scene_p, &settings, film, &cancel, &mut print_progress);
+    match &output.status {
+        RenderStatus::Finished => (),
+        RenderStatus::Cancelled => println!("Render cancelled, saving what was done"),
+        RenderStatus::Failed(e) => println!("Render failed: {}, saving what was done", e),
+    }
+    println!("{}", output.stats);
+    println!(
+        "{:.0} rays per second",
+        output.stats.total_rays() as f64 / output.time.as_secs_f64()
+    );
+    match output
+        .film
+        .image()
+        .save_with_format("test_img.png", ImageFormat::Png)
+    {
         Ok(_) => println!("Yay, managed to save!"),
         Err(e) => println!("Oh no!, {}", e),
     }
+    match &settings.heatmap {
+        None => (),
+        Some(filename) => match output
+            .film
+            .heatmap()
+            .save_with_format(filename, ImageFormat::Png)
+        {
+            Ok(_) => println!("Saved sample heatmap to {}", filename),
+            Err(e) => println!("Oh no!, {}", e),
+        },
+    }
+    match &settings.denoise {
+        None => (),
+        Some(denoise_settings) => {
+            let denoised = denoise::denoise(&output.film, denoise_settings);
+            match film::to_image(IMAGE_WIDTH, IMAGE_HEIGTH, &denoised)
+                .save_with_format(&denoise_settings.output, ImageFormat::Png)
+            {
+                Ok(_) => println!("Saved denoised image to {}", denoise_settings.output),
+                Err(e) => println!("Oh no!, {}", e),
+            }
+        }
+    }
+    match &settings.aovs {
+        None => (),
+        Some(prefix) => match output.film.save_aovs(prefix) {
+            Ok(_) => println!("Saved AOVs to {}_*", prefix),
+            Err(e) => println!("Oh no!, {}", e),
+        },
+    }
+}
+
+/// Redraw the progress bar on the current terminal line
+fn print_progress(progress: &Progress) {
+    const BAR_WIDTH: usize = 40;
+    let done = progress.fraction;
+    let filled = (done * BAR_WIDTH as f64) as usize;
+    print!(
+        "\r[{}{}] {:5.1}% pass {}, {} samples, {:.2}M rays/s, {} elapsed, ETA {}  ",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        100f64 * done,
+        progress.pass + 1,
+        progress.samples_done,
+        progress.rays_per_second / 1e6,
+        format_duration(progress.elapsed),
+        format_duration(progress.eta),
+    );
+    if progress.done {
+        println!();
+    }
+    std::io::stdout().flush().ok();
+}
+
+/// Duration as h:mm:ss
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
 }
@@ -0,0 +1,231 @@
+//! Participating media, which absorb and scatter light on its way between
+//! surfaces: fog filling the whole scene, and media filling the inside of
+//! closed objects. Media can vary in density following a voxel grid, and
+//! scatter light in new directions following the Henyey-Greenstein phase
+//! function. Where they absorb light they can also give off their own, like
+//! fire does.
+
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::local_to_world;
+use crate::vect::*;
+use crate::volume::VoxelGrid;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct Medium {
+    /// Fraction of light absorbed per unit of distance
+    pub sigma_a: f64,
+    /// Fraction of light scattered per unit of distance
+    pub sigma_s: f64,
+    /// Henyey-Greenstein asymmetry, from -1 scattering all light straight
+    /// back over 0 scattering it evenly to 1 letting it carry on
+    pub g: f64,
+    /// What sigma_a and sigma_s get multiplied by from place to place. The
+    /// density is one everywhere without a grid.
+    pub density: Option<Arc<VoxelGrid>>,
+    /// Radiance given off where the medium absorbs light
+    pub emission: Vect,
+    /// What emission gets multiplied by from place to place
+    pub emission_grid: Option<Arc<VoxelGrid>>,
+}
+
+impl Medium {
+    fn sigma_t(&self) -> f64 {
+        self.sigma_a + self.sigma_s
+    }
+
+    fn density_at(&self, pos: &Vect) -> f64 {
+        self.density.as_ref().map_or(1f64, |grid| grid.lookup(pos))
+    }
+
+    /// The highest density anywhere in the medium
+    fn max_density(&self) -> f64 {
+        self.density.as_ref().map_or(1f64, |grid| grid.max_value())
+    }
+
+    fn emission_at(&self, pos: &Vect) -> Vect {
+        match &self.emission_grid {
+            None => self.emission,
+            Some(grid) => self.emission.scalar_mul(&grid.lookup(pos)),
+        }
+    }
+
+    /// Fraction of the collisions with the medium that scatter the light
+    /// rather than absorb it
+    pub fn albedo(&self) -> f64 {
+        if self.sigma_t() <= 0f64 {
+            return 0f64;
+        }
+        self.sigma_s / self.sigma_t()
+    }
+
+    /// Distance along the ray to where it collides with the medium, if
+    /// that's before t_max, by delta tracking: tentative collisions are
+    /// spread evenly at the majorant, the medium's highest extinction, and
+    /// each one is real with probability the extinction there over the
+    /// majorant. Paths that go on past t_max don't need to be weighted, as
+    /// they do so with probability transmittance(t_max). Also returns an
+    /// estimate of the light the medium gives off along the way, added up
+    /// at every tentative collision.
+    pub fn sample_distance(
+        &self,
+        ray: &Ray,
+        t_max: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (Option<f64>, Vect) {
+        let max_density = self.max_density();
+        let majorant = self.sigma_t() * max_density;
+        let mut emitted = zero();
+        if majorant <= 0f64 {
+            return (None, emitted);
+        }
+        let mut t = 0f64;
+        loop {
+            t -= (1f64 - sampler.get_1d()).ln() / majorant;
+            if t >= t_max {
+                return (None, emitted);
+            }
+            let pos = ray.0.add(&ray.1.scalar_mul(&t));
+            let density = self.density_at(&pos);
+            if self.emission != zero() {
+                let absorbed = self.sigma_a * density / majorant;
+                emitted = emitted.add(&self.emission_at(&pos).scalar_mul(&absorbed));
+            }
+            // Homogeneous media are their own majorant, and every collision
+            // is a real one
+            if density >= max_density || sampler.get_1d() * max_density < density {
+                return (Some(t), emitted);
+            }
+        }
+    }
+
+    /// Fraction of light that gets through dist of the medium along the ray.
+    /// Estimated by ratio tracking, which multiplies in the chance of every
+    /// tentative collision up to dist being a null one, or exact in
+    /// homogeneous media.
+    pub fn transmittance(&self, ray: &Ray, dist: f64, sampler: &mut dyn Sampler) -> f64 {
+        if self.sigma_t() <= 0f64 {
+            return 1f64;
+        }
+        if self.density.is_none() {
+            return (-self.sigma_t() * dist).exp();
+        }
+        let max_density = self.max_density();
+        if max_density <= 0f64 {
+            return 1f64;
+        }
+        let majorant = self.sigma_t() * max_density;
+        let mut transmittance = 1f64;
+        let mut t = 0f64;
+        loop {
+            t -= (1f64 - sampler.get_1d()).ln() / majorant;
+            if t >= dist {
+                return transmittance;
+            }
+            let density = self.density_at(&ray.0.add(&ray.1.scalar_mul(&t)));
+            transmittance *= 1f64 - density / max_density;
+        }
+    }
+
+    /// Fraction of the light arriving from wi that scatters towards wo, per
+    /// steradian. Both point away from the point where it scatters.
+    pub fn phase(&self, wo: &Vect, wi: &Vect) -> f64 {
+        henyey_greenstein(-wo.dot(wi), self.g)
+    }
+
+    /// Direction for light to come from, distributed like phase()
+    pub fn sample_phase(&self, wo: &Vect, u1: f64, u2: f64) -> Vect {
+        let g = self.g;
+        // Cosine of the angle the light gets turned by
+        let cos_theta = if g.abs() < 1e-3 {
+            1f64 - 2f64 * u1
+        } else {
+            let s = (1f64 - g * g) / (1f64 - g + 2f64 * g * u1);
+            ((1f64 + g * g - s * s) / (2f64 * g)).clamp(-1f64, 1f64)
+        };
+        let sin_theta = (1f64 - cos_theta * cos_theta).max(0f64).sqrt();
+        let phi = 2f64 * PI * u2;
+        // Light carrying on unturned would come from straight behind wo
+        local_to_world(
+            &Vect(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta),
+            &wo.scalar_mul(&-1f64),
+        )
+    }
+}
+
+/// Density of light being turned by the angle with cosine cos_theta
+fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denominator = 1f64 + g * g - 2f64 * g * cos_theta;
+    (1f64 - g * g) / (4f64 * PI * denominator * denominator.sqrt())
+}
+
+#[test]
+fn phase_test() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand_pcg::Pcg32::seed_from_u64(0);
+    let wo = Vect(0.0, 0.6, 0.8);
+    for g in [-0.7, 0.0, 0.3, 0.9] {
+        let medium = Medium {
+            sigma_a: 0.0,
+            sigma_s: 1.0,
+            g,
+            density: None,
+            emission: zero(),
+            emission_grid: None,
+        };
+        // The phase function integrates to one over the sphere, and the mean
+        // cosine of the angle light is turned by is g
+        let n = 200000;
+        let mut integral = 0f64;
+        let mut mean_cos = 0f64;
+        for _ in 0..n {
+            let dir = crate::sampling::uniform_sample_sphere(rng.gen(), rng.gen());
+            integral += medium.phase(&wo, &dir) * 4f64 * PI;
+            let wi = medium.sample_phase(&wo, rng.gen(), rng.gen());
+            mean_cos += -wo.dot(&wi);
+        }
+        assert!((integral / n as f64 - 1f64).abs() < 0.05);
+        assert!((mean_cos / n as f64 - g).abs() < 0.01);
+    }
+}
+
+#[test]
+fn tracking_test() {
+    use crate::sampler;
+    // A unit cube getting denser along x, from 0 to 2, and a ray straight
+    // through it along x with 2 of extinction on the way
+    let grid = VoxelGrid::new(zero(), Vect(1.0, 1.0, 1.0), [2, 1, 1], vec![0.5, 1.5]);
+    let emission = Vect(1.0, 0.5, 0.25);
+    let medium = Medium {
+        sigma_a: 1.0,
+        sigma_s: 1.0,
+        g: 0.0,
+        density: Some(Arc::new(grid)),
+        emission,
+        emission_grid: None,
+    };
+    let ray = Ray(Vect(0.0, 0.5, 0.5), Vect(1.0, 0.0, 0.0));
+    let expected = (-2f64).exp();
+    let n = 20000;
+    let mut sampler = sampler::new(sampler::SamplerType::Independent, 0, n);
+    let (mut transmittance, mut escaped, mut emitted) = (0f64, 0, zero());
+    for i in 0..n {
+        sampler.start_sample(0, 0, i);
+        transmittance += medium.transmittance(&ray, 1f64, &mut *sampler);
+        let (collision, emission) = medium.sample_distance(&ray, 1f64, &mut *sampler);
+        if collision.is_none() {
+            escaped += 1;
+        }
+        emitted = emitted.add(&emission);
+    }
+    assert!((transmittance / n as f64 - expected).abs() < 0.01);
+    assert!((escaped as f64 / n as f64 - expected).abs() < 0.01);
+    // Half of the extinction is absorption, and each bit of emission gets
+    // out as far as the first collision
+    let absorbed_before_collision = 0.5 * (1f64 - expected);
+    let Vect(r, g, _) = emitted.scalar_mul(&(1f64 / n as f64));
+    assert!((r - absorbed_before_collision).abs() < 0.01);
+    assert!((g - 0.5 * absorbed_before_collision).abs() < 0.01);
+}
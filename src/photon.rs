@@ -0,0 +1,271 @@
+//! Photon mapping for caustics, after Jensen's "Realistic Image Synthesis
+//! Using Photon Mapping". Before the render, photons are sent out from the
+//! lights with a position and followed through mirrors and glass. Those
+//! that then land on anything else are kept in a kd-tree, and the path
+//! tracer adds the light they bring to every non-specular surface it hits,
+//! estimated from how densely they lie around it. Light from a point only
+//! gets there through a specular surface by a path the path tracer can't
+//! find, so none of it is counted twice. Photons go through participating
+//! media as if they weren't there.
+
+use crate::ray::Ray;
+use crate::sampler::sample_rng;
+use crate::stats::RenderStats;
+use crate::typedefs::{Intersection, Material, Scene};
+use crate::vect::*;
+use rand::Rng;
+use std::f64::consts::PI;
+
+pub struct Photon {
+    pub pos: Vect,
+    /// Direction the photon was travelling in when it landed
+    pub dir: Vect,
+    /// Flux the photon carries
+    pub power: Vect,
+}
+
+/// Photons in a kd-tree. Every range of photons is split at its middle
+/// one along axes[middle], with the photons below it on that axis to its
+/// left and the rest to its right.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    /// Photons this far from a point count towards the light there
+    radius: f64,
+}
+
+/// Send out photons from the lights with a position, for up to depth
+/// bounces each, and keep the caustic ones. Every photon sent out counts
+/// towards the power of the ones kept, so more of them give a smoother
+/// estimate rather than a brighter one. The rays followed are counted in
+/// stats.
+pub fn emit(
+    scene: &Scene,
+    photons: u32,
+    radius: f64,
+    depth: u8,
+    seed: u64,
+    stats: &mut RenderStats,
+) -> PhotonMap {
+    let local: Vec<usize> = (0..scene.1.len())
+        .filter(|i| scene.1[*i].position().is_some())
+        .collect();
+    let mut kept = Vec::new();
+    // Out of the way of the streams of every pixel
+    let mut rng = sample_rng(seed, u32::MAX, u32::MAX, 0);
+    for _ in 0..photons {
+        if local.is_empty() {
+            break;
+        }
+        let light = &scene.1[local[rng.gen_range(0..local.len())]];
+        let (pos, emission) = match (light.position(), light.emit(rng.gen(), rng.gen())) {
+            (Some(pos), Some(emission)) if emission.pdf > 0f64 => (pos, emission),
+            _ => continue,
+        };
+        let flux = emission.intensity * local.len() as f64 / (emission.pdf * photons as f64);
+        let mut power = Vect(1.0, 1.0, 1.0).scalar_mul(&flux);
+        let mut ray = Ray(pos, emission.dir);
+        let mut caustic = false;
+        for _ in 0..depth {
+            stats.photon_rays += 1;
+            let (intersection, material, _) = match ray.closest_hit(scene) {
+                Some(hit) => hit,
+                None => break,
+            };
+            if !material.is_specular() {
+                if caustic {
+                    kept.push(Photon {
+                        pos: intersection.pos,
+                        dir: ray.1,
+                        power,
+                    });
+                }
+                break;
+            }
+            let normal = intersection.shading_normal;
+            let wo = ray.1.scalar_mul(&-1f64);
+            let bsdf_sample = match material.sample(&normal, &wo, rng.gen(), rng.gen()) {
+                Some(bsdf_sample) => bsdf_sample,
+                None => break,
+            };
+            power = power.pointwise_mul(&bsdf_sample.weight(&normal));
+            ray = Ray::leaving(&intersection, bsdf_sample.wi);
+            caustic |= !matches!(material, Material::Interface);
+        }
+    }
+    PhotonMap::new(kept, radius)
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>, radius: f64) -> PhotonMap {
+        let mut axes = vec![0u8; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    /// Light the photons around the intersection bring to it, reflected
+    /// towards wo
+    pub fn radiance(&self, intersection: &Intersection, material: &Material, wo: &Vect) -> Vect {
+        let normal = intersection.normal;
+        let side = normal.dot(wo);
+        let mut res = zero();
+        self.lookup(0, self.photons.len(), &intersection.pos, &mut |photon| {
+            let wi = photon.dir.scalar_mul(&-1f64);
+            // Photons that landed on the other side of the surface
+            if normal.dot(&wi) * side <= 0f64 {
+                return;
+            }
+            res = res.add(
+                &material
+                    .eval(&intersection.shading_normal, wo, &wi)
+                    .pointwise_mul(&photon.power),
+            );
+        });
+        res.scalar_mul(&(1f64 / (PI * self.radius * self.radius)))
+    }
+
+    /// Call found with every photon in photons[lo..hi] within the radius of
+    /// pos
+    fn lookup(&self, lo: usize, hi: usize, pos: &Vect, found: &mut dyn FnMut(&Photon)) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        if photon.pos.sub(pos).norm_sq() <= self.radius * self.radius {
+            found(photon);
+        }
+        let axis = self.axes[mid];
+        let offset = component(pos, axis) - component(&photon.pos, axis);
+        if offset <= self.radius {
+            self.lookup(lo, mid, pos, found);
+        }
+        if offset >= -self.radius {
+            self.lookup(mid + 1, hi, pos, found);
+        }
+    }
+}
+
+/// Sort photons into a kd-tree, splitting along the axis they're most
+/// spread out on
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.is_empty() {
+        return;
+    }
+    let mut min = photons[0].pos;
+    let mut max = photons[0].pos;
+    for photon in photons.iter() {
+        let Vect(x, y, z) = photon.pos;
+        min = Vect(min.0.min(x), min.1.min(y), min.2.min(z));
+        max = Vect(max.0.max(x), max.1.max(y), max.2.max(z));
+    }
+    let Vect(dx, dy, dz) = max.sub(&min);
+    let axis = if dx >= dy && dx >= dz {
+        0
+    } else if dy >= dz {
+        1
+    } else {
+        2
+    };
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        component(&a.pos, axis).total_cmp(&component(&b.pos, axis))
+    });
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+fn component(v: &Vect, axis: u8) -> f64 {
+    match axis {
+        0 => v.0,
+        1 => v.1,
+        _ => v.2,
+    }
+}
+
+#[test]
+fn lookup_test() {
+    use rand::SeedableRng;
+    // The kd-tree finds the same photons as looking through all of them
+    let mut rng = rand_pcg::Pcg32::seed_from_u64(0);
+    let mut random_point = || Vect(rng.gen(), rng.gen(), rng.gen());
+    let points: Vec<Vect> = (0..1000).map(|_| random_point()).collect();
+    let queries: Vec<Vect> = (0..100).map(|_| random_point()).collect();
+    let photons = points
+        .iter()
+        .map(|pos| Photon {
+            pos: *pos,
+            dir: zero(),
+            power: zero(),
+        })
+        .collect();
+    let map = PhotonMap::new(photons, 0.1);
+    for query in &queries {
+        let mut found = 0;
+        map.lookup(0, map.len(), query, &mut |_| found += 1);
+        let expected = points
+            .iter()
+            .filter(|p| p.sub(query).norm_sq() <= 0.01)
+            .count();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn caustic_test() {
+    use crate::environment::Constant;
+    use crate::light::Pointlight;
+    use crate::plane::Plane;
+    // A point light between a floor and a mirror ceiling lights the floor
+    // a second time as if from its reflection, three units up
+    let albedo = 0.5;
+    let scene: Scene = (
+        vec![
+            Box::new(Plane {
+                point: zero(),
+                normal: Vect(0.0, 1.0, 0.0),
+                material: Material::Lambertian(Vect(albedo, albedo, albedo)),
+                surface_map: None,
+            }),
+            Box::new(Plane {
+                point: Vect(0.0, 2.0, 0.0),
+                normal: Vect(0.0, -1.0, 0.0),
+                material: Material::Mirror,
+                surface_map: None,
+            }),
+        ],
+        vec![Box::new(Pointlight {
+            pos: Vect(0.0, 1.0, 0.0),
+            intensity: 1f64,
+        })],
+        Box::new(Constant(zero())),
+        None,
+    );
+    let mut stats = RenderStats::default();
+    let map = emit(&scene, 200000, 0.3, 4, 0, &mut stats);
+    // Every photon goes at least as far as the first surface
+    assert!(stats.photon_rays >= 200000);
+    let intersection = Intersection {
+        pos: zero(),
+        normal: Vect(0.0, 1.0, 0.0),
+        shading_normal: Vect(0.0, 1.0, 0.0),
+        uv: (0f64, 0f64),
+        uv_scale: (0f64, 0f64),
+        tangent: zero(),
+    };
+    let material = Material::Lambertian(Vect(albedo, albedo, albedo));
+    let radiance = map.radiance(&intersection, &material, &Vect(0.0, 1.0, 0.0));
+    let expected = albedo / PI / (4f64 * PI * 9f64);
+    assert!((radiance.0 - expected).abs() < 0.15 * expected);
+}
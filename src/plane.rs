@@ -1,5 +1,7 @@
 use crate::geometry::Geometry;
 use crate::ray::*;
+use crate::sampling::orthonormal_basis;
+use crate::texture::SurfaceMap;
 use crate::typedefs::{Intersection, Material};
 use crate::vect::*;
 
@@ -7,6 +9,7 @@ pub struct Plane {
     pub point: Vect,
     pub normal: Vect,
     pub material: Material,
+    pub surface_map: Option<SurfaceMap>,
 }
 
 impl Geometry for Plane {
@@ -22,31 +25,37 @@ impl Geometry for Plane {
         let Ray(rpos, rdir) = ray;
         let raydirdotplanenormal = rdir.dot(&self.normal);
         if raydirdotplanenormal == 0f64 {
-            return Intersection {
-                pos: zero(),
-                normal: zero(),
-            };
+            return Intersection::miss();
         }
         let t = -(rpos.sub(&self.point).dot(&self.normal)) / raydirdotplanenormal;
         if t < 0f64 {
-            return Intersection {
-                pos: zero(),
-                normal: zero(),
-            };
+            return Intersection::miss();
         }
-        if raydirdotplanenormal < 0f64 {
-            Intersection {
-                pos: rpos.add(&rdir.scalar_mul(&t)),
-                normal: self.normal,
-            }
+        let pos = rpos.add(&rdir.scalar_mul(&t));
+        // uv are distances along two directions in the plane
+        let (tangent, bitangent) = orthonormal_basis(&self.normal.normalise());
+        let offset = pos.sub(&self.point);
+        let uv = (offset.dot(&tangent), offset.dot(&bitangent));
+        let normal = if raydirdotplanenormal < 0f64 {
+            self.normal
         } else {
-            Intersection {
-                pos: rpos.add(&rdir.scalar_mul(&t)),
-                normal: self.normal.scalar_mul(&-1f64),
-            }
+            self.normal.scalar_mul(&-1f64)
+        };
+        Intersection {
+            pos,
+            normal,
+            shading_normal: normal,
+            uv,
+            uv_scale: (1f64, 1f64),
+            tangent,
         }
     }
+
     fn get_material(&self) -> Material {
         self.material
     }
+
+    fn get_surface_map(&self) -> Option<&SurfaceMap> {
+        self.surface_map.as_ref()
+    }
 }
@@ -0,0 +1,581 @@
+//! A principled material after Burley's "Physically Based Shading at
+//! Disney", covering most materials with the one set of parameters DCC
+//! tools export. It layers a Burley diffuse base with sheen, GGX reflection
+//! going from dielectric to metal, rough glass transmission after Walter et
+//! al.'s "Microfacet Models for Refraction" and a clearcoat on top. Each
+//! sample picks one of these lobes in proportion to roughly how much light
+//! it reflects, and pdf() is the combined density of all of them. Below
+//! SMOOTH_ALPHA the reflection and glass lobes are perfectly sharp, and
+//! left to sample() alone.
+
+use crate::bsdf::{fresnel_dielectric, reflect, refract, BsdfSample};
+use crate::sampling::*;
+use crate::vect::*;
+use std::f64::consts::PI;
+
+/// Roughness of the clearcoat, in the same units as Principled::roughness
+const CLEARCOAT_ROUGHNESS: f64 = 0.25;
+/// GGX widths below this are too narrow for floating point
+const MIN_ALPHA: f64 = 1e-3;
+/// GGX widths below which reflection and transmission are taken to be
+/// perfectly smooth, like bsdf::MIRROR_SHININESS for Phong
+const SMOOTH_ALPHA: f64 = 1e-2;
+
+#[derive(Copy, Clone)]
+pub struct Principled {
+    /// Diffuse colour of dielectrics, the colour of reflections off metals
+    /// and the tint of light going through
+    pub base_colour: Vect,
+    /// From 0 for dielectrics to 1 for metals
+    pub metallic: f64,
+    /// From 0 for mirror-like reflections to 1 for matte ones
+    pub roughness: f64,
+    /// Strength of reflections off dielectrics, 0.5 reflecting 4% head on
+    pub specular: f64,
+    /// Strength of a white glossy coat on top, like varnish
+    pub clearcoat: f64,
+    /// Strength of the extra light cloth reflects at grazing angles
+    pub sheen: f64,
+    /// From 0 for opaque materials to 1 for glass-like ones
+    pub transmission: f64,
+    /// Index of refraction of the inside, for transmission
+    pub ior: f64,
+    /// Radiance the surface gives off. It only lights the scene through
+    /// paths that happen to hit it.
+    pub emission: Vect,
+}
+
+impl Principled {
+    pub fn eval(&self, normal: &Vect, wo: &Vect, wi: &Vect) -> Vect {
+        let (cos_o, cos_i) = (normal.dot(wo), normal.dot(wi));
+        if cos_o * cos_i <= 0f64 {
+            return self.eval_transmission(normal, wo, wi);
+        }
+        if cos_o < 0f64 {
+            // Only light going through reaches the inside
+            return self.eval_glass_reflection(normal, wo, wi);
+        }
+        let weights = self.lobe_weights();
+        let h = wo.add(wi).normalise();
+        let cos_d = wi.dot(&h);
+        let alpha = self.alpha();
+        let mut res = zero();
+        if weights.diffuse > 0f64 {
+            let fd90 = 0.5 + 2f64 * self.roughness * cos_d * cos_d;
+            let retro = (1f64 + (fd90 - 1f64) * schlick_weight(cos_i))
+                * (1f64 + (fd90 - 1f64) * schlick_weight(cos_o));
+            let sheen = self.sheen * schlick_weight(cos_d);
+            let diffuse = self
+                .base_colour
+                .scalar_mul(&(retro / PI))
+                .add(&Vect(sheen, sheen, sheen));
+            res = res.add(&diffuse.scalar_mul(&weights.diffuse));
+        }
+        if weights.specular > 0f64 && !self.smooth() {
+            let fresnel = schlick(&self.specular_colour(), cos_d);
+            let microfacet =
+                ggx(normal, &h, alpha) * smith(normal, wo, wi, &h, alpha) / (4f64 * cos_o * cos_i);
+            res = res.add(&fresnel.scalar_mul(&(weights.specular * microfacet)));
+        }
+        if weights.clearcoat > 0f64 {
+            let alpha = clearcoat_alpha();
+            let fresnel = 0.04 + 0.96 * schlick_weight(cos_d);
+            let coat = fresnel * ggx(normal, &h, alpha) * smith(normal, wo, wi, &h, alpha)
+                / (4f64 * cos_o * cos_i);
+            res = res.add(&Vect(coat, coat, coat).scalar_mul(&weights.clearcoat));
+        }
+        if weights.glass > 0f64 {
+            let glass = self.eval_glass_reflection(normal, wo, wi);
+            res = res.add(&glass.scalar_mul(&weights.glass));
+        }
+        res
+    }
+
+    pub fn pdf(&self, normal: &Vect, wo: &Vect, wi: &Vect) -> f64 {
+        let (cos_o, cos_i) = (normal.dot(wo), normal.dot(wi));
+        let probabilities = self.lobe_probabilities(normal, wo);
+        if cos_o * cos_i <= 0f64 {
+            return probabilities.glass * self.glass_pdf(normal, wo, wi);
+        }
+        if cos_o < 0f64 {
+            return self.glass_pdf(normal, wo, wi);
+        }
+        let h = wo.add(wi).normalise();
+        let reflection_pdf =
+            |alpha: f64| ggx(normal, &h, alpha) * normal.dot(&h) / (4f64 * wo.dot(&h).abs());
+        let specular_pdf = if self.smooth() {
+            0f64
+        } else {
+            reflection_pdf(self.alpha())
+        };
+        probabilities.diffuse * cosine_hemisphere_pdf(cos_i)
+            + probabilities.specular * specular_pdf
+            + probabilities.clearcoat * reflection_pdf(clearcoat_alpha())
+            + probabilities.glass * self.glass_pdf(normal, wo, wi)
+    }
+
+    pub fn sample(&self, normal: &Vect, wo: &Vect, u1: f64, u2: f64) -> Option<BsdfSample> {
+        let p = self.lobe_probabilities(normal, wo);
+        let cos_o = normal.dot(wo);
+        // Sharp lobes pick their one direction, weighted by the fraction of
+        // light going that way over the chance of picking it
+        let delta = |wi: Vect, weight: Vect| {
+            Some(BsdfSample {
+                wi,
+                f: weight.scalar_mul(&(1f64 / wi.dot(normal).abs())),
+                pdf: 1f64,
+                specular: true,
+            })
+        };
+        if self.smooth() && u1 >= p.diffuse && u1 < p.diffuse + p.specular {
+            let fresnel = schlick(&self.specular_colour(), cos_o);
+            let weight = self.lobe_weights().specular / p.specular;
+            return delta(reflect(wo, normal), fresnel.scalar_mul(&weight));
+        }
+        if self.smooth() && u1 >= p.diffuse + p.specular + p.clearcoat && p.glass > 0f64 {
+            let u1 = ((u1 - (1f64 - p.glass)) / p.glass).clamp(0f64, 1f64);
+            let reflect_probability = self.reflect_probability(normal, wo);
+            let weight = if cos_o > 0f64 {
+                self.lobe_weights().glass / p.glass
+            } else {
+                1f64
+            };
+            let reflectance = fresnel_dielectric(cos_o, self.ior);
+            if u1 < reflect_probability {
+                let weight = weight * reflectance / reflect_probability;
+                return delta(reflect(wo, normal), Vect(weight, weight, weight));
+            }
+            let weight = weight * (1f64 - reflectance) / (1f64 - reflect_probability);
+            return delta(
+                refract(wo, normal, self.ior)?,
+                self.tint().scalar_mul(&weight),
+            );
+        }
+        // u1 picks the lobe and then gets stretched back over [0, 1)
+        let (wi, through) = if u1 < p.diffuse {
+            let wi = local_to_world(&cosine_sample_hemisphere(u1 / p.diffuse, u2), normal);
+            (wi, false)
+        } else if u1 < p.diffuse + p.specular {
+            let u1 = (u1 - p.diffuse) / p.specular;
+            (
+                reflect(wo, &sample_ggx(normal, self.alpha(), u1, u2)),
+                false,
+            )
+        } else if u1 < p.diffuse + p.specular + p.clearcoat {
+            let u1 = (u1 - p.diffuse - p.specular) / p.clearcoat;
+            (
+                reflect(wo, &sample_ggx(normal, clearcoat_alpha(), u1, u2)),
+                false,
+            )
+        } else if p.glass > 0f64 {
+            let u1 = ((u1 - (1f64 - p.glass)) / p.glass).clamp(0f64, 1f64);
+            let reflect_probability = self.reflect_probability(normal, wo);
+            if u1 < reflect_probability {
+                let u1 = u1 / reflect_probability;
+                (
+                    reflect(wo, &sample_ggx(normal, self.alpha(), u1, u2)),
+                    false,
+                )
+            } else {
+                let u1 = (u1 - reflect_probability) / (1f64 - reflect_probability);
+                let h = sample_ggx(normal, self.alpha(), u1, u2);
+                // Seen from behind, the microfacet would bend the light the
+                // wrong way
+                if wo.dot(&h) * cos_o <= 0f64 {
+                    return None;
+                }
+                (refract(wo, &h, self.ior)?, true)
+            }
+        } else {
+            return None;
+        };
+        // Steep microfacets can reflect light through the surface or refract
+        // it back, which pdf() leaves out
+        if (normal.dot(&wi) * cos_o < 0f64) != through {
+            return None;
+        }
+        let pdf = self.pdf(normal, wo, &wi);
+        if pdf <= 0f64 {
+            return None;
+        }
+        Some(BsdfSample {
+            wi,
+            f: self.eval(normal, wo, &wi),
+            pdf,
+            specular: false,
+        })
+    }
+
+    /// Colour of the material, for the albedo AOV and the debug integrator
+    pub fn albedo(&self) -> Vect {
+        self.base_colour
+    }
+
+    fn alpha(&self) -> f64 {
+        (self.roughness * self.roughness).max(MIN_ALPHA)
+    }
+
+    /// Whether the reflection and glass lobes are perfectly sharp
+    fn smooth(&self) -> bool {
+        self.alpha() < SMOOTH_ALPHA
+    }
+
+    /// What light going through the surface once gets multiplied by, so
+    /// that going in and out again tints it by the base colour
+    fn tint(&self) -> Vect {
+        Vect(
+            self.base_colour.0.sqrt(),
+            self.base_colour.1.sqrt(),
+            self.base_colour.2.sqrt(),
+        )
+    }
+
+    /// Reflectance head on of the specular lobe, from that of a dielectric
+    /// for specular up to the base colour for metals
+    fn specular_colour(&self) -> Vect {
+        let dielectric = 0.08 * self.specular;
+        Vect(dielectric, dielectric, dielectric)
+            .scalar_mul(&(1f64 - self.metallic))
+            .add(&self.base_colour.scalar_mul(&self.metallic))
+    }
+
+    /// How much each lobe counts towards the material from the outside.
+    /// Light going through replaces the diffuse base and dielectric
+    /// reflections, which the glass lobe brings back on its own.
+    fn lobe_weights(&self) -> Lobes {
+        let dielectric = 1f64 - self.metallic;
+        Lobes {
+            diffuse: dielectric * (1f64 - self.transmission),
+            specular: 1f64 - dielectric * self.transmission,
+            clearcoat: 0.25 * self.clearcoat,
+            glass: dielectric * self.transmission,
+        }
+    }
+
+    /// Chance of sampling each lobe for light leaving towards wo, adding up
+    /// to one. From the inside only the glass lobe is sampled.
+    fn lobe_probabilities(&self, normal: &Vect, wo: &Vect) -> Lobes {
+        let cos_o = normal.dot(wo);
+        if cos_o < 0f64 {
+            return Lobes {
+                diffuse: 0f64,
+                specular: 0f64,
+                clearcoat: 0f64,
+                glass: 1f64,
+            };
+        }
+        let weights = self.lobe_weights();
+        let max = |v: &Vect| v.0.max(v.1).max(v.2);
+        let specular = max(&schlick(&self.specular_colour(), cos_o));
+        let probabilities = Lobes {
+            diffuse: weights.diffuse * (max(&self.base_colour) + self.sheen),
+            specular: weights.specular * specular,
+            clearcoat: weights.clearcoat * (0.04 + 0.96 * schlick_weight(cos_o)),
+            glass: weights.glass,
+        };
+        let total = probabilities.diffuse
+            + probabilities.specular
+            + probabilities.clearcoat
+            + probabilities.glass;
+        if total <= 0f64 {
+            return Lobes {
+                diffuse: 1f64,
+                specular: 0f64,
+                clearcoat: 0f64,
+                glass: 0f64,
+            };
+        }
+        Lobes {
+            diffuse: probabilities.diffuse / total,
+            specular: probabilities.specular / total,
+            clearcoat: probabilities.clearcoat / total,
+            glass: probabilities.glass / total,
+        }
+    }
+
+    /// Glass lobe for wo and wi on the same side of the surface
+    fn eval_glass_reflection(&self, normal: &Vect, wo: &Vect, wi: &Vect) -> Vect {
+        if self.smooth() {
+            return zero();
+        }
+        let (cos_o, cos_i) = (normal.dot(wo), normal.dot(wi));
+        let h = facing(normal, &wo.add(wi));
+        let alpha = self.alpha();
+        let reflectance = fresnel_dielectric(wo.dot(&h), self.ior);
+        let f = reflectance * ggx(normal, &h, alpha) * smith(normal, wo, wi, &h, alpha)
+            / (4f64 * (cos_o * cos_i).abs());
+        Vect(f, f, f)
+    }
+
+    /// Light coming through the surface from wi and leaving towards wo,
+    /// tinted by tint(). Scaled like Glass, so that the radiance doesn't
+    /// change going through.
+    fn eval_transmission(&self, normal: &Vect, wo: &Vect, wi: &Vect) -> Vect {
+        if self.smooth() {
+            return zero();
+        }
+        let (cos_o, cos_i) = (normal.dot(wo), normal.dot(wi));
+        let weight = if cos_o > 0f64 {
+            self.lobe_weights().glass
+        } else {
+            1f64
+        };
+        if weight <= 0f64 || cos_o * cos_i >= 0f64 {
+            return zero();
+        }
+        let (eta_o, eta_i) = self.etas(cos_o);
+        let h = facing(normal, &wo.scalar_mul(&eta_o).add(&wi.scalar_mul(&eta_i)));
+        let (dot_o, dot_i) = (wo.dot(&h), wi.dot(&h));
+        // Both have to be on the same side of the microfacet as of the
+        // surface
+        if dot_o * cos_o <= 0f64 || dot_i * cos_i <= 0f64 {
+            return zero();
+        }
+        let alpha = self.alpha();
+        let denominator = eta_o * dot_o + eta_i * dot_i;
+        let transmittance = 1f64 - fresnel_dielectric(dot_o, self.ior);
+        let f = (dot_o * dot_i).abs()
+            * eta_i
+            * eta_i
+            * transmittance
+            * ggx(normal, &h, alpha)
+            * smith(normal, wo, wi, &h, alpha)
+            / ((cos_o * cos_i).abs() * denominator * denominator);
+        self.tint().scalar_mul(&(weight * f))
+    }
+
+    /// Density with which the glass lobe picks wi
+    fn glass_pdf(&self, normal: &Vect, wo: &Vect, wi: &Vect) -> f64 {
+        if self.smooth() {
+            return 0f64;
+        }
+        let (cos_o, cos_i) = (normal.dot(wo), normal.dot(wi));
+        let alpha = self.alpha();
+        if cos_o * cos_i > 0f64 {
+            let h = facing(normal, &wo.add(wi));
+            return self.reflect_probability(normal, wo) * ggx(normal, &h, alpha) * normal.dot(&h)
+                / (4f64 * wo.dot(&h).abs());
+        }
+        let (eta_o, eta_i) = self.etas(cos_o);
+        let h = facing(normal, &wo.scalar_mul(&eta_o).add(&wi.scalar_mul(&eta_i)));
+        let (dot_o, dot_i) = (wo.dot(&h), wi.dot(&h));
+        if dot_o * cos_o <= 0f64 || dot_i * cos_i <= 0f64 {
+            return 0f64;
+        }
+        let denominator = eta_o * dot_o + eta_i * dot_i;
+        (1f64 - self.reflect_probability(normal, wo))
+            * ggx(normal, &h, alpha)
+            * normal.dot(&h)
+            * eta_i
+            * eta_i
+            * dot_i.abs()
+            / (denominator * denominator)
+    }
+
+    /// Chance of the glass lobe reflecting rather than refracting, going by
+    /// the reflectance of the surface as a whole. Kept off zero and one,
+    /// as some microfacets still let through light the surface as a whole
+    /// would reflect, and the other way round.
+    fn reflect_probability(&self, normal: &Vect, wo: &Vect) -> f64 {
+        fresnel_dielectric(normal.dot(wo), self.ior).clamp(0.05, 0.95)
+    }
+
+    /// Indices of refraction on the side of wo and on the other side
+    fn etas(&self, cos_o: f64) -> (f64, f64) {
+        if cos_o > 0f64 {
+            (1f64, self.ior)
+        } else {
+            (self.ior, 1f64)
+        }
+    }
+}
+
+struct Lobes {
+    diffuse: f64,
+    specular: f64,
+    clearcoat: f64,
+    glass: f64,
+}
+
+fn clearcoat_alpha() -> f64 {
+    CLEARCOAT_ROUGHNESS * CLEARCOAT_ROUGHNESS
+}
+
+/// v normalised and turned to the side of the normal
+fn facing(normal: &Vect, v: &Vect) -> Vect {
+    let v = v.normalise();
+    if v.dot(normal) < 0f64 {
+        v.scalar_mul(&-1f64)
+    } else {
+        v
+    }
+}
+
+fn schlick_weight(cos: f64) -> f64 {
+    (1f64 - cos.abs()).clamp(0f64, 1f64).powi(5)
+}
+
+/// Schlick's approximation of the Fresnel reflectance of a material with
+/// reflectance f0 head on
+fn schlick(f0: &Vect, cos: f64) -> Vect {
+    let w = schlick_weight(cos);
+    f0.scalar_mul(&(1f64 - w)).add(&Vect(w, w, w))
+}
+
+/// GGX density of microfacet normals h, of width alpha
+fn ggx(normal: &Vect, h: &Vect, alpha: f64) -> f64 {
+    let cos = normal.dot(h);
+    if cos <= 0f64 {
+        return 0f64;
+    }
+    let a2 = alpha * alpha;
+    let d = cos * cos * (a2 - 1f64) + 1f64;
+    a2 / (PI * d * d)
+}
+
+/// Smith shadowing and masking of wo and wi by the GGX microfacets
+fn smith(normal: &Vect, wo: &Vect, wi: &Vect, h: &Vect, alpha: f64) -> f64 {
+    let g1 = |v: &Vect| {
+        let cos = normal.dot(v);
+        if v.dot(h) * cos <= 0f64 {
+            return 0f64;
+        }
+        let a2 = alpha * alpha;
+        2f64 * cos.abs() / (cos.abs() + (a2 + (1f64 - a2) * cos * cos).sqrt())
+    };
+    g1(wo) * g1(wi)
+}
+
+/// Microfacet normal with density ggx() times its cosine with the normal
+fn sample_ggx(normal: &Vect, alpha: f64, u1: f64, u2: f64) -> Vect {
+    let tan_sq = alpha * alpha * u1 / (1f64 - u1).max(1e-12);
+    let cos_theta = 1f64 / (1f64 + tan_sq).sqrt();
+    let sin_theta = (1f64 - cos_theta * cos_theta).max(0f64).sqrt();
+    let phi = 2f64 * PI * u2;
+    local_to_world(
+        &Vect(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta),
+        normal,
+    )
+}
+
+#[test]
+fn sampling_test() {
+    // Every lobe samples directions with the density pdf() says, so
+    // integrating f cos / pdf over samples gives the same reflectance as
+    // uniform sampling, and none of them reflect more light than arrives
+    let base = Principled {
+        base_colour: Vect(0.8, 0.8, 0.8),
+        metallic: 0f64,
+        roughness: 0.5,
+        specular: 0.5,
+        clearcoat: 0f64,
+        sheen: 0f64,
+        transmission: 0f64,
+        ior: 1.5,
+        emission: zero(),
+    };
+    let materials = [
+        base,
+        Principled {
+            metallic: 1f64,
+            roughness: 0.4,
+            ..base
+        },
+        Principled {
+            clearcoat: 1f64,
+            roughness: 0.8,
+            ..base
+        },
+        Principled {
+            transmission: 1f64,
+            base_colour: Vect(1.0, 1.0, 1.0),
+            ..base
+        },
+    ];
+    let normal = Vect(0.0, 1.0, 0.0);
+    for material in &materials {
+        for wo in [
+            Vect(0.0, 1.0, 1.0),
+            Vect(0.3, 1.0, 0.0),
+            Vect(0.0, -1.0, 0.5),
+            Vect(0.0, -0.1, 1.0),
+        ] {
+            let wo = wo.normalise();
+            if wo.dot(&normal) < 0f64 && material.transmission == 0f64 {
+                continue;
+            }
+            let n = 400;
+            let (mut sampled, mut uniform) = (0f64, 0f64);
+            for i in 0..n {
+                for j in 0..n {
+                    let u1 = (i as f64 + 0.5) / n as f64;
+                    let u2 = (j as f64 + 0.5) / n as f64;
+                    if let Some(s) = material.sample(&normal, &wo, u1, u2) {
+                        assert!((s.pdf - material.pdf(&normal, &wo, &s.wi)).abs() < 1e-9 * s.pdf);
+                        sampled += s.weight(&normal).0;
+                    }
+                    let wi = uniform_sample_sphere(u1, u2);
+                    uniform +=
+                        material.eval(&normal, &wo, &wi).0 * wi.dot(&normal).abs() * 4f64 * PI;
+                }
+            }
+            let (sampled, uniform) = (sampled / (n * n) as f64, uniform / (n * n) as f64);
+            assert!(uniform < 1.02, "{}", uniform);
+            assert!((sampled - uniform).abs() < 0.02, "{} {}", sampled, uniform);
+        }
+    }
+}
+
+#[test]
+fn smooth_test() {
+    // Below SMOOTH_ALPHA reflections are perfect mirror images, and glass
+    // lets through whatever it doesn't reflect
+    let glass = Principled {
+        base_colour: Vect(1.0, 1.0, 1.0),
+        metallic: 0f64,
+        roughness: 0f64,
+        specular: 0.5,
+        clearcoat: 0f64,
+        sheen: 0f64,
+        transmission: 1f64,
+        ior: 1.5,
+        emission: zero(),
+    };
+    let metal = Principled {
+        base_colour: Vect(0.9, 0.6, 0.3),
+        metallic: 1f64,
+        transmission: 0f64,
+        ..glass
+    };
+    let normal = Vect(0.0, 1.0, 0.0);
+    for wo in [
+        Vect(0.0, 1.0, 1.0),
+        Vect(0.0, -1.0, 0.5),
+        Vect(0.0, -1.0, 3.0),
+    ] {
+        let wo = wo.normalise();
+        let n = 1000;
+        let mut through = 0f64;
+        for i in 0..n {
+            let u1 = (i as f64 + 0.5) / n as f64;
+            if let Some(s) = glass.sample(&normal, &wo, u1, 0.5) {
+                assert!(s.specular);
+                through += s.weight(&normal).0;
+            }
+        }
+        assert!((through / n as f64 - 1f64).abs() < 1e-9);
+        assert_eq!(glass.eval(&normal, &wo, &reflect(&wo, &normal)), zero());
+    }
+    let wo = Vect(0.0, 1.0, 1.0).normalise();
+    let s = metal.sample(&normal, &wo, 0.5, 0.5).unwrap();
+    assert!(s.specular);
+    assert!(s.wi.sub(&reflect(&wo, &normal)).norm() < 1e-12);
+    assert!(
+        s.weight(&normal)
+            .sub(&schlick(&metal.base_colour, wo.dot(&normal)))
+            .norm()
+            < 1e-12
+    );
+    assert_eq!(metal.eval(&normal, &wo, &s.wi), zero());
+    assert_eq!(metal.pdf(&normal, &wo, &s.wi), 0f64);
+}
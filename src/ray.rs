@@ -1,12 +1,14 @@
+use crate::medium::Medium;
+use crate::sampler::Sampler;
+use crate::settings::RenderSettings;
+use crate::stats::RenderStats;
+use crate::subsurface::random_walk;
 use crate::typedefs::{Intersection, Material, Scene};
 use crate::vect::*;
-use rand::prelude::*;
-use std::f64::consts::PI;
-
-const PI2: f64 = PI * PI;
 
 /// A ray (pos, dir) is a half-line in the scene space that starts
 /// from pos and goes towards dir.
+#[derive(Clone, Copy)]
 pub struct Ray(pub Vect, pub Vect);
 
 impl std::fmt::Display for Ray {
@@ -16,90 +18,708 @@ impl std::fmt::Display for Ray {
     }
 }
 
+/// What a path traced from the camera found
+pub struct PathSample {
+    /// Radiance arriving along the path
+    pub colour: Vect,
+    /// The part of colour that was reflected diffusely at most once on its
+    /// way, the rest is indirect light
+    pub direct: Vect,
+    pub first_hit: Option<FirstHit>,
+    /// Light the path traced onto other pixels, by their (row, col)
+    pub splats: Vec<((u32, u32), Vect)>,
+}
+
+/// Where a ray stops going straight
+#[allow(clippy::large_enum_variant)]
+pub enum Interaction<'a> {
+    /// The closest hit on a surface, as from closest_hit
+    Surface(Intersection, Material, usize),
+    /// A point where the ray collides with the medium it's going through
+    Medium(Vect, &'a Medium),
+}
+
+/// The surface a path hits first
+pub struct FirstHit {
+    pub pos: Vect,
+    pub normal: Vect,
+    pub albedo: Vect,
+    /// Index of the object in the scene
+    pub object: usize,
+}
+
 impl Ray {
-    pub fn colour(&self, scene: &Scene, depth: u8) -> Vect {
-        if depth <= 0u8 {
-            return Vect(0.0, 0.0, 0.0);
+    /// Radiance arriving at the origin of the ray from its direction,
+    /// estimated by following a single random path through the scene.
+    /// From settings.min_bounces on the path is randomly cut short when it
+    /// carries little light (Russian roulette), which is what ends it; it
+    /// only gets cut off after settings.depth bounces as a safety net.
+    /// Caustics from settings.photon_map, if there is one, are added at
+    /// every non-specular hit. The camera has to be outside of every object
+    /// with a medium. The rays it takes are counted in stats.
+    pub fn trace(
+        &self,
+        scene: &Scene,
+        settings: &RenderSettings,
+        sampler: &mut dyn Sampler,
+        stats: &mut RenderStats,
+    ) -> PathSample {
+        let mut res = zero();
+        // What res was when the path first bounced diffusely
+        let mut direct_res = None;
+        let mut first_hit = None;
+        // How much of the light arriving at the current ray's origin makes
+        // it back to the camera
+        let mut throughput = Vect(1.0, 1.0, 1.0);
+        let mut ray = *self;
+        // Set when ray was spawned by a diffuse bounce. The environment has
+        // already been sampled directly at that bounce, so we mustn't count
+        // it a second time if the ray escapes the scene.
+        let mut after_diffuse = false;
+        let mut medium = scene.3.as_ref();
+        stats.camera_rays += 1;
+        for bounce in 0..settings.depth {
+            stats.rays += 1;
+            let wo = ray.1.scalar_mul(&-1f64);
+            let (interaction, emitted) = ray.interact(scene, &mut medium, sampler, stats);
+            res = res.add(&throughput.pointwise_mul(&emitted));
+            let (intersection, material, object) = match interaction {
+                Some(Interaction::Surface(intersection, material, object)) => {
+                    (intersection, material, object)
+                }
+                Some(Interaction::Medium(pos, medium)) => {
+                    throughput = throughput.scalar_mul(&medium.albedo());
+                    let direct = medium_direct_light(&pos, medium, &wo, scene, sampler, stats);
+                    res = res.add(&throughput.pointwise_mul(&direct));
+                    if direct_res.is_none() {
+                        direct_res = Some(res);
+                    }
+                    let (u1, u2) = sampler.get_2d();
+                    ray = Ray(pos, medium.sample_phase(&wo, u1, u2));
+                    // The environment wasn't sampled directly here
+                    after_diffuse = false;
+                    match roulette(&throughput, bounce, settings, sampler) {
+                        Some(scale) => throughput = throughput.scalar_mul(&scale),
+                        None => break,
+                    }
+                    continue;
+                }
+                None => {
+                    if !after_diffuse {
+                        res = res.add(&throughput.pointwise_mul(&scene.2.radiance(&ray.1)));
+                    }
+                    break;
+                }
+            };
+            res = res.add(&throughput.pointwise_mul(&material.emission()));
+            if bounce == 0 {
+                first_hit = Some(FirstHit {
+                    pos: intersection.pos,
+                    normal: intersection.shading_normal,
+                    albedo: material.albedo(),
+                    object,
+                });
+            }
+            // Light leaves subsurface materials from somewhere else, as if
+            // from a white Lambertian surface there
+            let (intersection, material, wo) = match material {
+                Material::Subsurface(colour, radius) => {
+                    match random_walk(
+                        scene,
+                        object,
+                        &intersection,
+                        &colour,
+                        &radius,
+                        sampler,
+                        stats,
+                    ) {
+                        Some((exit, weight)) => {
+                            throughput = throughput.pointwise_mul(&weight);
+                            let normal = exit.shading_normal;
+                            (exit, Material::Lambertian(Vect(1.0, 1.0, 1.0)), normal)
+                        }
+                        None => break,
+                    }
+                }
+                _ => (intersection, material, wo),
+            };
+            let normal = intersection.shading_normal;
+            if !material.is_specular() {
+                let (direct, _) =
+                    direct_light(&intersection, &material, &wo, medium, scene, sampler, stats);
+                res = res.add(&throughput.pointwise_mul(&direct));
+                if let Some(photon_map) = &settings.photon_map {
+                    let caustics = photon_map.radiance(&intersection, &material, &wo);
+                    res = res.add(&throughput.pointwise_mul(&caustics));
+                }
+            }
+            let (u1, u2) = sampler.get_2d();
+            let bsdf_sample = match material.sample(&normal, &wo, u1, u2) {
+                Some(bsdf_sample) => bsdf_sample,
+                None => break,
+            };
+            if !bsdf_sample.specular && direct_res.is_none() {
+                direct_res = Some(res);
+            }
+            throughput = throughput.pointwise_mul(&bsdf_sample.weight(&normal));
+            let geometric = intersection.normal;
+            if bsdf_sample.wi.dot(&geometric) * wo.dot(&geometric) < 0f64 {
+                medium = medium_across(scene, object, &geometric, &bsdf_sample.wi);
+            }
+            ray = Ray::leaving(&intersection, bsdf_sample.wi);
+            after_diffuse = !bsdf_sample.specular;
+            match roulette(&throughput, bounce, settings, sampler) {
+                Some(scale) => throughput = throughput.scalar_mul(&scale),
+                None => break,
+            }
         }
-        let Ray(rpos, _) = &self;
-        let mut closest_intersection = Intersection {
-            normal: zero(),
-            pos: zero(),
-        };
-        let mut closest_dsquared = std::f64::INFINITY;
+        PathSample {
+            colour: res,
+            direct: direct_res.unwrap_or(res),
+            first_hit,
+            splats: Vec::new(),
+        }
+    }
+
+    /// The closest intersection of the ray with the scene, if any, the
+    /// material there and the index of the object hit. The position is
+    /// nudged off the surface so that rays leaving from it don't hit the same
+    /// surface again, and the shading normal is tilted by the surface map.
+    pub fn closest_hit(&self, scene: &Scene) -> Option<(Intersection, Material, usize)> {
+        let Ray(rpos, _) = self;
+        let mut closest_intersection = Intersection::miss();
+        let mut closest_dsquared = f64::INFINITY;
         let mut closest_geo_material: Material = Material::Lambertian(zero());
-        for geo in &scene.0 {
-            let intersection = geo.intersect(&self);
+        let mut closest_object = 0;
+        for (i, geo) in scene.0.iter().enumerate() {
+            let intersection = geo.intersect(self);
             if intersection.normal != zero() {
                 let dsquared = rpos.sub(&intersection.pos).norm_sq();
                 if dsquared < closest_dsquared {
                     closest_dsquared = dsquared;
                     closest_intersection = intersection;
                     closest_geo_material = geo.get_material();
+                    closest_object = i;
                 }
             }
         }
-        let closest_intersection = Intersection {
-            normal: closest_intersection.normal,
-            pos: closest_intersection
-                .pos
-                .add(&closest_intersection.normal.scalar_mul(&crate::EPSILON)),
+        if closest_dsquared == f64::INFINITY {
+            return None;
+        }
+        let shading_normal = match scene.0[closest_object].get_surface_map() {
+            Some(map) => map.shading_normal(&closest_intersection),
+            None => closest_intersection.normal,
         };
-        if closest_dsquared < std::f64::INFINITY {
-            match closest_geo_material {
-                Material::Lambertian(albedo) => {
-                    let mut tot_light = 0.0;
-                    for light in &scene.1 {
-                        tot_light += light.get_contribution(&closest_intersection, &scene);
-                    }
-                    if tot_light <= 0.1 {
-                        return Vect(255.0, 0.0, 250.0);
-                    }
-                    let l0 = albedo.scalar_mul(&tot_light);
-                    let rand_dir = box_muller_random_vector(&closest_intersection.normal);
-                    let w1 = Ray(closest_intersection.pos, rand_dir);
-                    return l0.add(&albedo.pointwise_mul(&w1.colour(scene, depth - 1)));
+        Some((
+            Intersection {
+                normal: closest_intersection.normal,
+                shading_normal,
+                pos: closest_intersection
+                    .pos
+                    .add(&closest_intersection.normal.scalar_mul(&crate::EPSILON)),
+                uv: closest_intersection.uv,
+                uv_scale: closest_intersection.uv_scale,
+                tangent: closest_intersection.tangent,
+            },
+            closest_geo_material,
+            closest_object,
+        ))
+    }
+
+    /// What the ray runs into first, going through boundaries between media
+    /// on the way, and the light the media give off on the way there.
+    /// medium is the one the ray starts off in, and becomes the one it ends
+    /// up in. None if the ray leaves the scene.
+    pub fn interact<'a>(
+        &self,
+        scene: &'a Scene,
+        medium: &mut Option<&'a Medium>,
+        sampler: &mut dyn Sampler,
+        stats: &mut RenderStats,
+    ) -> (Option<Interaction<'a>>, Vect) {
+        let mut ray = *self;
+        let mut emitted = zero();
+        loop {
+            let hit = ray.closest_hit(scene);
+            if let Some(inside) = *medium {
+                let t_max = hit.as_ref().map_or(f64::INFINITY, |(intersection, _, _)| {
+                    intersection.pos.sub(&ray.0).norm()
+                });
+                let (collision, emission) = inside.sample_distance(&ray, t_max, sampler);
+                emitted = emitted.add(&emission);
+                if let Some(t) = collision {
+                    let pos = ray.0.add(&ray.1.scalar_mul(&t));
+                    return (Some(Interaction::Medium(pos, inside)), emitted);
+                }
+            }
+            match hit {
+                Some((intersection, Material::Interface, object)) => {
+                    *medium = medium_across(scene, object, &intersection.normal, &ray.1);
+                    ray = Ray::leaving(&intersection, ray.1);
+                    stats.rays += 1;
                 }
-                Material::Mirror => {
-                    return self
-                        .reflect(&Ray(closest_intersection.pos, closest_intersection.normal))
-                        .colour(scene, depth - 1);
+                Some((intersection, material, object)) => {
+                    return (
+                        Some(Interaction::Surface(intersection, material, object)),
+                        emitted,
+                    )
                 }
+                None => return (None, emitted),
             }
         }
-        Vect(50.0, 0.0, 0.0)
     }
 
-    // Identify Ray (pos, dir) with the hyperplane H that contains pos and
-    // for all x in H, x.dot(dir) == 0
-    pub fn reflect(&self, hyperplane: &Ray) -> Ray {
-        let Ray(hpos, hdir) = *hyperplane;
-        let Ray(_spos, sdir) = *self;
-        let l = -2f64 * sdir.dot(&hdir);
-        Ray(hpos, sdir.add(&hdir.scalar_mul(&l)))
+    /// Ray leaving an intersection found by closest_hit in direction dir.
+    /// The position there is nudged off the surface along the normal, so
+    /// rays going into the surface start from just under it instead.
+    pub fn leaving(intersection: &Intersection, dir: Vect) -> Ray {
+        if dir.dot(&intersection.normal) >= 0f64 {
+            return Ray(intersection.pos, dir);
+        }
+        let under = intersection.normal.scalar_mul(&(-2f64 * crate::EPSILON));
+        Ray(intersection.pos.add(&under), dir)
+    }
+
+    /// Whether anything in the scene lies on this ray closer than max_dist
+    /// to its origin. The ray is counted in stats as a shadow ray.
+    pub fn occluded(&self, scene: &Scene, max_dist: f64, stats: &mut RenderStats) -> bool {
+        stats.shadow_rays += 1;
+        let Ray(rpos, _) = self;
+        for geo in &scene.0 {
+            let intersection = geo.intersect(self);
+            if intersection.normal != zero() && intersection.pos.sub(rpos).norm() < max_dist {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Fraction of the light that makes it max_dist along the ray, through
+    /// the media on the way starting with medium. Zero if any surface but
+    /// the boundary of a medium is in the way. Each stretch of the ray
+    /// between boundaries is counted in stats as a shadow ray.
+    pub fn transmittance(
+        &self,
+        scene: &Scene,
+        max_dist: f64,
+        medium: Option<&Medium>,
+        sampler: &mut dyn Sampler,
+        stats: &mut RenderStats,
+    ) -> f64 {
+        let mut ray = *self;
+        let mut medium = medium;
+        let mut left = max_dist;
+        let mut transmittance = 1f64;
+        loop {
+            stats.shadow_rays += 1;
+            let hit = ray
+                .closest_hit(scene)
+                .filter(|(intersection, _, _)| intersection.pos.sub(&ray.0).norm() < left);
+            let dist = hit.as_ref().map_or(left, |(intersection, _, _)| {
+                intersection.pos.sub(&ray.0).norm()
+            });
+            if let Some(inside) = medium {
+                transmittance *= inside.transmittance(&ray, dist, sampler);
+            }
+            match hit {
+                None => return transmittance,
+                Some((intersection, Material::Interface, object)) => {
+                    medium = medium_across(scene, object, &intersection.normal, &ray.1);
+                    ray = Ray::leaving(&intersection, ray.1);
+                    left -= dist;
+                }
+                Some(_) => return 0f64,
+            }
+        }
     }
 }
 
-fn box_muller_random_vector(normal: &Vect) -> Vect {
-    let r1: f64 = random();
-    let r2: f64 = random();
-    let sqrt1mr2 = (1f64 - r2).sqrt();
-    let twopir1 = PI2 * r1;
-    let x = (twopir1.cos()) * sqrt1mr2;
-    let y = (twopir1.sin()) * sqrt1mr2;
-    let z = r2.sqrt();
-    let xabs = x.abs();
-    let yabs = y.abs();
-    let zabs = z.abs();
-    let t1 = if xabs <= yabs && xabs <= zabs {
-        Vect(0.0, -z, y).normalise()
-    } else if yabs <= xabs && yabs <= zabs {
-        Vect(-z, 0.0, x).normalise()
+/// The medium a ray going in direction dir through the surface of object
+/// ends up in: the object's own going in, and the fog coming out
+fn medium_across<'a>(
+    scene: &'a Scene,
+    object: usize,
+    normal: &Vect,
+    dir: &Vect,
+) -> Option<&'a Medium> {
+    if dir.dot(normal) < 0f64 {
+        scene.0[object].get_medium()
     } else {
-        Vect(-y, x, 0.0).normalise()
+        scene.3.as_ref()
+    }
+}
+
+/// Russian roulette, from settings.min_bounces on: randomly end the path
+/// when it carries little light, and make up for it in the throughput of
+/// the ones that carry on. What to scale the throughput by if the path
+/// carries on, None if it ends.
+pub fn roulette(
+    throughput: &Vect,
+    bounce: u8,
+    settings: &RenderSettings,
+    sampler: &mut dyn Sampler,
+) -> Option<f64> {
+    if bounce + 1 < settings.min_bounces {
+        return Some(1f64);
+    }
+    let Vect(r, g, b) = *throughput;
+    let survival = r.max(g).max(b).min(0.95);
+    if sampler.get_1d() >= survival {
+        return None;
+    }
+    Some(1f64 / survival)
+}
+
+/// Light arriving at a surface straight from the light sources and the
+/// environment through medium, then reflected towards wo. Also returns the
+/// total strength of the light arriving, before it gets reflected.
+pub fn direct_light(
+    intersection: &Intersection,
+    material: &Material,
+    wo: &Vect,
+    medium: Option<&Medium>,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> (Vect, f64) {
+    let normal = intersection.shading_normal;
+    let mut tot_light = 0f64;
+    let mut direct = zero();
+    for light in &scene.1 {
+        let (u1, u2) = sampler.get_2d();
+        if let Some(sample) =
+            light.get_contribution(intersection, scene, medium, sampler, stats, u1, u2)
+        {
+            tot_light += sample.strength;
+            direct = direct.add(
+                &material
+                    .eval(&normal, wo, &sample.dir)
+                    .scalar_mul(&sample.strength),
+            );
+        }
+    }
+    if let Some((dir, env_light)) = sample_environment(intersection, medium, scene, sampler, stats)
+    {
+        tot_light += env_light.norm();
+        direct = direct.add(&material.eval(&normal, wo, &dir).pointwise_mul(&env_light));
+    }
+    (direct, tot_light)
+}
+
+/// Light arriving at a surface straight from the environment through
+/// medium: the direction it comes from and an estimate of the irradiance it
+/// brings, that is the radiance times the cosine factor divided by the pdf.
+pub fn sample_environment(
+    intersection: &Intersection,
+    medium: Option<&Medium>,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> Option<(Vect, Vect)> {
+    let (u1, u2) = sampler.get_2d();
+    let (dir, pdf) = scene.2.sample(&intersection.shading_normal, u1, u2);
+    let cos = intersection.shading_normal.dot(&dir);
+    // A tilted shading normal can lean over directions under the surface
+    if pdf <= 0f64 || cos <= 0f64 || intersection.normal.dot(&dir) <= 0f64 {
+        return None;
+    }
+    let transmittance =
+        Ray(intersection.pos, dir).transmittance(scene, f64::INFINITY, medium, sampler, stats);
+    if transmittance <= 0f64 {
+        return None;
+    }
+    Some((
+        dir,
+        scene
+            .2
+            .radiance(&dir)
+            .scalar_mul(&(transmittance * cos / pdf)),
+    ))
+}
+
+/// Light arriving at pos in medium straight from the light sources, then
+/// scattered towards wo. The environment is left to paths that leave the
+/// scene.
+fn medium_direct_light(
+    pos: &Vect,
+    medium: &Medium,
+    wo: &Vect,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> Vect {
+    let mut direct = 0f64;
+    for light in &scene.1 {
+        let (u1, u2) = sampler.get_2d();
+        let sample = light.sample(pos, u1, u2);
+        if sample.strength <= 0f64 {
+            continue;
+        }
+        let transmittance =
+            Ray(*pos, sample.dir).transmittance(scene, sample.dist, Some(medium), sampler, stats);
+        direct += medium.phase(wo, &sample.dir) * sample.strength * transmittance;
+    }
+    Vect(direct, direct, direct)
+}
+
+#[test]
+fn white_furnace_test() {
+    // A Lambertian sphere lit by a uniform environment reflects exactly the
+    // light it receives times its albedo, so a white one disappears.
+    use crate::environment::Constant;
+    use crate::sampler;
+    use crate::sphere::Sphere;
+    let emission = 1000f64;
+    for albedo in [1f64, 0.5] {
+        let scene: Scene = (
+            vec![Box::new(Sphere {
+                pos: zero(),
+                radius: 1f64,
+                material: Material::Lambertian(Vect(albedo, albedo, albedo)),
+                medium: None,
+                surface_map: None,
+            })],
+            vec![],
+            Box::new(Constant(Vect(emission, emission, emission))),
+            None,
+        );
+        let settings = RenderSettings {
+            depth: 50,
+            ..RenderSettings::default()
+        };
+        let mut sampler = sampler::new(settings.sampler, settings.seed, 1000);
+        let mut tot = zero();
+        let mut stats = RenderStats::default();
+        for i in 0..1000 {
+            sampler.start_sample(0, 0, i);
+            let dir = Vect(0.3 * (i as f64 / 1000f64 - 0.5), 0.1, 1f64).normalise();
+            let path =
+                Ray(Vect(0.0, 0.0, -5.0), dir).trace(&scene, &settings, &mut *sampler, &mut stats);
+            tot = tot.add(&path.colour);
+            // Nothing reaches a convex object after bouncing off it
+            assert_eq!(path.direct, path.colour);
+            let hit = path.first_hit.unwrap();
+            assert_eq!(hit.albedo, Vect(albedo, albedo, albedo));
+            assert_eq!(hit.object, 0);
+        }
+        let Vect(r, g, b) = tot.scalar_mul(&(1f64 / 1000f64));
+        for c in [r, g, b] {
+            assert!((c - albedo * emission).abs() < 1e-6 * emission, "{}", c);
+        }
+        assert_eq!(stats.camera_rays, 1000);
+        assert!(stats.rays > stats.camera_rays && stats.shadow_rays > 0);
+    }
+}
+
+#[test]
+fn white_furnace_concave_test() {
+    // Light bounces around between white objects without getting lost, so
+    // in a uniform environment they still disappear, on average. Shadowed
+    // creases must not be treated any differently.
+    use crate::environment::Constant;
+    use crate::plane::Plane;
+    use crate::sampler;
+    use crate::sphere::Sphere;
+    let emission = 1000f64;
+    let white = Material::Lambertian(Vect(1.0, 1.0, 1.0));
+    let scene: Scene = (
+        vec![
+            Box::new(Sphere {
+                pos: Vect(-1.0, 0.0, 0.0),
+                radius: 1f64,
+                material: white,
+                medium: None,
+                surface_map: None,
+            }),
+            Box::new(Sphere {
+                pos: Vect(1.0, 0.0, 0.0),
+                radius: 1f64,
+                material: white,
+                medium: None,
+                surface_map: None,
+            }),
+            Box::new(Plane {
+                point: Vect(0.0, -1.0, 0.0),
+                normal: Vect(0.0, 1.0, 0.0),
+                material: white,
+                surface_map: None,
+            }),
+        ],
+        vec![],
+        Box::new(Constant(Vect(emission, emission, emission))),
+        None,
+    );
+    let settings = RenderSettings {
+        depth: 100,
+        ..RenderSettings::default()
+    };
+    let n = 4000;
+    let mut sampler = sampler::new(settings.sampler, settings.seed, n);
+    let mut tot = zero();
+    let mut stats = RenderStats::default();
+    for i in 0..n {
+        sampler.start_sample(0, 0, i);
+        // Into the crease between the spheres and the floor
+        let dir = Vect(0.2 * (i as f64 / n as f64 - 0.5), -0.15, 1f64).normalise();
+        let path =
+            Ray(Vect(0.0, 0.0, -5.0), dir).trace(&scene, &settings, &mut *sampler, &mut stats);
+        tot = tot.add(&path.colour);
+    }
+    let Vect(r, _, _) = tot.scalar_mul(&(1f64 / n as f64));
+    assert!((r - emission).abs() < 0.02 * emission, "{}", r);
+}
+
+#[test]
+fn medium_test() {
+    // A ball of smoke that only scatters disappears in a uniform environment
+    // like a white object, and one that only absorbs lets through the light
+    // it doesn't absorb on the way through its middle.
+    use crate::environment::Constant;
+    use crate::sampler;
+    use crate::sphere::Sphere;
+    let smoke = |medium| -> Scene {
+        (
+            vec![Box::new(Sphere {
+                pos: zero(),
+                radius: 1f64,
+                material: Material::Interface,
+                medium: Some(medium),
+                surface_map: None,
+            })],
+            vec![],
+            Box::new(Constant(Vect(1.0, 1.0, 1.0))),
+            None,
+        )
+    };
+    let settings = RenderSettings {
+        depth: 100,
+        ..RenderSettings::default()
     };
-    let t2 = t1.cross(normal);
-    t1.scalar_mul(&x)
-        .add(&t2.scalar_mul(&y))
-        .add(&normal.scalar_mul(&z))
+    let n = 2000;
+    let mut sampler = sampler::new(settings.sampler, settings.seed, n);
+    let mut stats = RenderStats::default();
+    let mut mean = |scene: &Scene| {
+        let mut tot = 0f64;
+        for i in 0..n {
+            sampler.start_sample(0, 0, i);
+            let path = Ray(Vect(0.0, 0.0, -5.0), Vect(0.0, 0.0, 1.0)).trace(
+                scene,
+                &settings,
+                &mut *sampler,
+                &mut stats,
+            );
+            tot += path.colour.0;
+        }
+        tot / n as f64
+    };
+    let scattering = mean(&smoke(Medium {
+        sigma_a: 0.0,
+        sigma_s: 2.0,
+        g: 0.5,
+        density: None,
+        emission: zero(),
+        emission_grid: None,
+    }));
+    assert!((scattering - 1f64).abs() < 0.03, "{}", scattering);
+    let absorbing = mean(&smoke(Medium {
+        sigma_a: 0.5,
+        sigma_s: 0.0,
+        g: 0.0,
+        density: None,
+        emission: zero(),
+        emission_grid: None,
+    }));
+    assert!((absorbing - (-1f64).exp()).abs() < 0.03, "{}", absorbing);
+}
+
+#[test]
+fn closed_box_test() {
+    // Inside a closed box all the light reflected off the walls lands on
+    // other walls, so with a point light of power I and walls of albedo a
+    // and total area A, the light leaving them averages a I / ((1 - a) A).
+    // The walls are seen head on, at points spread evenly over the floor.
+    // Russian roulette ends the paths, and mustn't change that even when it
+    // starts straight away.
+    use crate::environment::Constant;
+    use crate::light::Pointlight;
+    use crate::plane::Plane;
+    use crate::sampler;
+    use std::f64::consts::PI;
+    let intensity = 6000f64 * PI;
+    for (albedo, min_bounces) in [(0.8, 3), (0.5, 0)] {
+        let material = Material::Lambertian(Vect(albedo, albedo, albedo));
+        let wall = |normal: Vect| -> Box<dyn crate::geometry::Geometry + Send + Sync> {
+            Box::new(Plane {
+                point: normal.scalar_mul(&-1f64),
+                normal,
+                material,
+                surface_map: None,
+            })
+        };
+        let scene: Scene = (
+            vec![
+                wall(Vect(1.0, 0.0, 0.0)),
+                wall(Vect(-1.0, 0.0, 0.0)),
+                wall(Vect(0.0, 1.0, 0.0)),
+                wall(Vect(0.0, -1.0, 0.0)),
+                wall(Vect(0.0, 0.0, 1.0)),
+                wall(Vect(0.0, 0.0, -1.0)),
+            ],
+            vec![Box::new(Pointlight {
+                pos: zero(),
+                intensity,
+            })],
+            Box::new(Constant(zero())),
+            None,
+        );
+        // Six walls of area 4, and radiance is the light leaving over pi
+        let expected = albedo * intensity / ((1f64 - albedo) * 24f64 * PI);
+        let settings = RenderSettings {
+            min_bounces,
+            ..RenderSettings::default()
+        };
+        let n = 64;
+        let mut sampler = sampler::new(settings.sampler, settings.seed, n * n);
+        let mut tot = zero();
+        let mut stats = RenderStats::default();
+        for i in 0..n * n {
+            sampler.start_sample(0, 0, i);
+            let x = 2f64 * ((i % n) as f64 + 0.5) / n as f64 - 1f64;
+            let z = 2f64 * ((i / n) as f64 + 0.5) / n as f64 - 1f64;
+            let ray = Ray(Vect(x, 0.5, z), Vect(0.0, -1.0, 0.0));
+            tot = tot.add(
+                &ray.trace(&scene, &settings, &mut *sampler, &mut stats)
+                    .colour,
+            );
+        }
+        let Vect(r, _, _) = tot.scalar_mul(&(1f64 / (n * n) as f64));
+        assert!((r - expected).abs() < 0.02 * expected, "{} {}", r, expected);
+    }
+}
+
+#[test]
+fn surface_map_normal_test() {
+    // A bump map only tilts the normal shading goes by, and rays leaving
+    // the surface are still nudged along the surface's own normal
+    use crate::environment::Constant;
+    use crate::plane::Plane;
+    use crate::texture::{SurfaceMap, Texture};
+    let ramp = (0..4).map(|x| Vect(x as f64 / 4f64, 0.0, 0.0)).collect();
+    let scene: Scene = (
+        vec![Box::new(Plane {
+            point: zero(),
+            normal: Vect(0.0, 1.0, 0.0),
+            material: Material::Lambertian(Vect(0.5, 0.5, 0.5)),
+            surface_map: Some(SurfaceMap::Bump(Texture::new(4, 1, ramp, 1f64), 1f64)),
+        })],
+        vec![],
+        Box::new(Constant(zero())),
+        None,
+    );
+    let (hit, _, _) = Ray(Vect(0.3, 1.0, 0.2), Vect(0.0, -1.0, 0.0))
+        .closest_hit(&scene)
+        .unwrap();
+    assert_eq!(hit.normal, Vect(0.0, 1.0, 0.0));
+    assert!(hit.shading_normal.dot(&hit.normal) < 0.99);
+    assert_eq!(hit.pos.1, crate::EPSILON);
 }
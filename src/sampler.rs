@@ -0,0 +1,431 @@
+//! Samplers hand out the uniform random numbers used while rendering a
+//! pixel: where in the pixel the camera ray goes, which point of a light to
+//! sample and which way a ray bounces. Every call uses up one dimension of
+//! the sample. Samplers other than the independent one place the numbers of
+//! a dimension so that the samples of a pixel cover [0, 1) more evenly than
+//! independent draws would, which reduces noise.
+//!
+//! Everything only depends on the seed, the pixel, the sample index and the
+//! dimension, so renders are reproducible whatever the thread scheduling.
+
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+pub trait Sampler {
+    /// Start generating sample number index of the pixel at (row, col).
+    /// Resets the dimension back to zero.
+    fn start_sample(&mut self, row: u32, col: u32, index: u32);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplerType {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+/// Build a sampler of the given type. spp is the number of samples that
+/// will be taken per pixel, samples past it are still valid but no longer
+/// stratified.
+pub fn new(sampler_type: SamplerType, seed: u64, spp: u32) -> Box<dyn Sampler + Send> {
+    match sampler_type {
+        SamplerType::Independent => Box::new(IndependentSampler {
+            seed,
+            rng: Pcg32::new(seed, 0),
+        }),
+        SamplerType::Stratified => Box::new(StratifiedSampler {
+            seed,
+            spp,
+            state: SampleState::default(),
+        }),
+        SamplerType::Halton => Box::new(HaltonSampler {
+            seed,
+            state: SampleState::default(),
+        }),
+        SamplerType::Sobol => Box::new(SobolSampler {
+            seed,
+            state: SampleState::default(),
+        }),
+    }
+}
+
+/// SplitMix64 finaliser, scrambles the bits of x
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0x9e3779b97f4a7c15, |h, v| mix(h ^ mix(*v)))
+}
+
+/// Uniform number in [0, 1) from the top 53 bits of a hash
+fn hash_to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn u32_to_unit(x: u32) -> f64 {
+    x as f64 / (1u64 << 32) as f64
+}
+
+/// Random number generator for one sample of one pixel
+pub fn sample_rng(seed: u64, row: u32, col: u32, index: u32) -> Pcg32 {
+    let pixel = ((row as u64) << 32) | col as u64;
+    Pcg32::new(mix(seed ^ mix(pixel)), index as u64)
+}
+
+/// Independent uniform random numbers, one PCG stream per pixel sample
+pub struct IndependentSampler {
+    seed: u64,
+    rng: Pcg32,
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, row: u32, col: u32, index: u32) {
+        self.rng = sample_rng(self.seed, row, col, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.rng.gen(), self.rng.gen())
+    }
+}
+
+/// Where the deterministic samplers are at
+#[derive(Default)]
+struct SampleState {
+    row: u32,
+    col: u32,
+    index: u32,
+    dim: u32,
+}
+
+impl SampleState {
+    fn start(&mut self, row: u32, col: u32, index: u32) {
+        *self = SampleState {
+            row,
+            col,
+            index,
+            dim: 0,
+        };
+    }
+
+    /// Hash of the current pixel and dimension, the same for every sample
+    /// index so it can scramble a whole pixel's worth of samples
+    /// consistently.
+    fn dim_hash(&self, seed: u64, salt: u64) -> u64 {
+        hash(&[
+            seed,
+            self.row as u64,
+            self.col as u64,
+            self.dim as u64,
+            salt,
+        ])
+    }
+
+    /// Uniform number unique to the current sample and dimension
+    fn random(&self, seed: u64, salt: u64) -> f64 {
+        hash_to_unit(hash(&[
+            seed,
+            self.row as u64,
+            self.col as u64,
+            self.dim as u64,
+            self.index as u64,
+            salt,
+        ]))
+    }
+}
+
+/// Kensler's hash based permutation of [0, l), from "Correlated
+/// Multi-Jittered Sampling". Which permutation is chosen by p.
+fn permute(i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    let mut i = i;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+/// Jittered sampling: each dimension is split into spp strata (a grid for
+/// 2D dimensions) and every sample of the pixel lands in a different one.
+pub struct StratifiedSampler {
+    seed: u64,
+    spp: u32,
+    state: SampleState,
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, row: u32, col: u32, index: u32) {
+        self.state.start(row, col, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let s = &self.state;
+        let res = if s.index < self.spp {
+            let stratum = permute(s.index, self.spp, s.dim_hash(self.seed, 0) as u32);
+            (stratum as f64 + s.random(self.seed, 1)) / self.spp as f64
+        } else {
+            s.random(self.seed, 1)
+        };
+        self.state.dim += 1;
+        res
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let s = &self.state;
+        let nx = (self.spp as f64).sqrt() as u32;
+        let ny = self.spp / nx.max(1);
+        // The strata are shuffled over all spp samples, samples that land
+        // past the nx * ny grid are uniform over the whole square instead.
+        let stratum = if s.index < self.spp {
+            permute(s.index, self.spp, s.dim_hash(self.seed, 0) as u32)
+        } else {
+            u32::MAX
+        };
+        let jx = s.random(self.seed, 1);
+        let jy = s.random(self.seed, 2);
+        let res = if stratum < nx * ny {
+            (
+                ((stratum % nx) as f64 + jx) / nx as f64,
+                ((stratum / nx) as f64 + jy) / ny as f64,
+            )
+        } else {
+            (jx, jy)
+        };
+        self.state.dim += 1;
+        res
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+/// Mirror the digits of index in the given base around the decimal point
+fn radical_inverse(base: u32, index: u32) -> f64 {
+    let inv_base = 1f64 / base as f64;
+    let mut inv = inv_base;
+    let mut i = index;
+    let mut res = 0f64;
+    while i > 0 {
+        res += (i % base) as f64 * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    res
+}
+
+/// Halton sequence, dimension d uses the radical inverse in the d-th prime.
+/// Each pixel gets its own random toroidal shift of the sequence so
+/// neighbouring pixels don't share the same pattern. Dimensions past the
+/// prime table fall back to independent numbers.
+pub struct HaltonSampler {
+    seed: u64,
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    fn next(&mut self) -> f64 {
+        let s = &self.state;
+        let res = match PRIMES.get(s.dim as usize) {
+            Some(base) => {
+                let shift = hash_to_unit(s.dim_hash(self.seed, 0));
+                (radical_inverse(*base, s.index) + shift).fract()
+            }
+            None => s.random(self.seed, 1),
+        };
+        self.state.dim += 1;
+        res
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, row: u32, col: u32, index: u32) {
+        self.state.start(row, col, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.next()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.next(), self.next())
+    }
+}
+
+/// Laine-Karras style hash, a random permutation that only lets bits
+/// affect the bits above them.
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Owen scrambling of a 32 bit fixed point number, see Burley 2020,
+/// "Practical Hash-based Owen Scrambling".
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// First two dimensions of the Sobol sequence, as 32 bit fixed point
+fn sobol_2d(index: u32) -> (u32, u32) {
+    let mut v = 1u32 << 31;
+    let mut y = 0u32;
+    let mut i = index;
+    while i != 0 {
+        if i & 1 != 0 {
+            y ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    (index.reverse_bits(), y)
+}
+
+/// Owen scrambled Sobol. Every dimension of the sample is a separately
+/// shuffled and scrambled copy of the first one or two Sobol dimensions
+/// ("padding"), which keeps the good 2D distribution without needing a
+/// table of direction numbers.
+pub struct SobolSampler {
+    seed: u64,
+    state: SampleState,
+}
+
+impl SobolSampler {
+    fn shuffled_index(&self) -> u32 {
+        owen_scramble(self.state.index, self.state.dim_hash(self.seed, 0) as u32)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, row: u32, col: u32, index: u32) {
+        self.state.start(row, col, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let (x, _) = sobol_2d(self.shuffled_index());
+        let res = u32_to_unit(owen_scramble(x, self.state.dim_hash(self.seed, 1) as u32));
+        self.state.dim += 1;
+        res
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let (x, y) = sobol_2d(self.shuffled_index());
+        let res = (
+            u32_to_unit(owen_scramble(x, self.state.dim_hash(self.seed, 1) as u32)),
+            u32_to_unit(owen_scramble(y, self.state.dim_hash(self.seed, 2) as u32)),
+        );
+        self.state.dim += 1;
+        res
+    }
+}
+
+#[test]
+fn sample_rng_test() {
+    let a: f64 = sample_rng(0, 1, 2, 3).gen();
+    assert_eq!(a, sample_rng(0, 1, 2, 3).gen::<f64>());
+    assert_ne!(a, sample_rng(0, 2, 1, 3).gen::<f64>());
+    assert_ne!(a, sample_rng(0, 1, 2, 4).gen::<f64>());
+    assert_ne!(a, sample_rng(1, 1, 2, 3).gen::<f64>());
+}
+
+#[test]
+fn stratification_test() {
+    // 16 samples of a pixel should hit each cell of a 4x4 grid exactly once,
+    // in every dimension.
+    for sampler_type in [SamplerType::Stratified, SamplerType::Sobol] {
+        let mut sampler = new(sampler_type, 7, 16);
+        for dim in 0..5 {
+            let mut cells = [0; 16];
+            for index in 0..16 {
+                sampler.start_sample(3, 4, index);
+                for _ in 0..dim {
+                    sampler.get_2d();
+                }
+                let (x, y) = sampler.get_2d();
+                assert!((0f64..1f64).contains(&x) && (0f64..1f64).contains(&y));
+                cells[(x * 4f64) as usize + 4 * (y * 4f64) as usize] += 1;
+            }
+            assert_eq!(cells, [1; 16], "{:?} dimension {}", sampler_type, dim);
+        }
+    }
+}
+
+#[test]
+fn stratification_1d_test() {
+    for sampler_type in [SamplerType::Stratified, SamplerType::Sobol] {
+        let mut sampler = new(sampler_type, 7, 8);
+        let mut cells = [0; 8];
+        for index in 0..8 {
+            sampler.start_sample(3, 4, index);
+            sampler.get_2d();
+            cells[(sampler.get_1d() * 8f64) as usize] += 1;
+        }
+        assert_eq!(cells, [1; 8], "{:?}", sampler_type);
+    }
+}
+
+#[test]
+fn sampler_determinism_test() {
+    for sampler_type in [
+        SamplerType::Independent,
+        SamplerType::Stratified,
+        SamplerType::Halton,
+        SamplerType::Sobol,
+    ] {
+        let mut sampler = new(sampler_type, 1, 4);
+        let draw = |sampler: &mut Box<dyn Sampler + Send>, index| {
+            sampler.start_sample(10, 20, index);
+            (0..100)
+                .map(|_| sampler.get_2d())
+                .collect::<Vec<(f64, f64)>>()
+        };
+        let a = draw(&mut sampler, 2);
+        let _ = draw(&mut sampler, 3);
+        assert_eq!(a, draw(&mut sampler, 2));
+        assert!(a
+            .iter()
+            .all(|(x, y)| (0f64..1f64).contains(x) && (0f64..1f64).contains(y)));
+    }
+}
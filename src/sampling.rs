@@ -0,0 +1,198 @@
+//! Helpers for drawing samples from non-uniform distributions. Everything
+//! takes uniform random numbers in [0, 1) as arguments so that the caller
+//! decides where the randomness comes from.
+
+use crate::vect::*;
+use std::f64::consts::PI;
+
+/// Two unit vectors that together with the unit vector n form an
+/// orthonormal basis. Branchless construction from Duff et al. 2017,
+/// "Building an Orthonormal Basis, Revisited".
+pub fn orthonormal_basis(n: &Vect) -> (Vect, Vect) {
+    let Vect(x, y, z) = *n;
+    let sign = 1f64.copysign(z);
+    let a = -1f64 / (sign + z);
+    let b = x * y * a;
+    (
+        Vect(1f64 + sign * x * x * a, sign * b, -sign * x),
+        Vect(b, sign + y * y * a, -y),
+    )
+}
+
+/// Express a vector given in the frame where n is the z axis in world
+/// coordinates.
+pub fn local_to_world(v: &Vect, n: &Vect) -> Vect {
+    let (t1, t2) = orthonormal_basis(n);
+    t1.scalar_mul(&v.0)
+        .add(&t2.scalar_mul(&v.1))
+        .add(&n.scalar_mul(&v.2))
+}
+
+/// Uniformly distributed direction within cos_max of the z axis.
+/// The pdf is 1 / (2 pi (1 - cos_max)).
+pub fn uniform_sample_cone(u1: f64, u2: f64, cos_max: f64) -> Vect {
+    let cos_theta = 1f64 - u1 * (1f64 - cos_max);
+    let sin_theta = (1f64 - cos_theta * cos_theta).max(0f64).sqrt();
+    let phi = 2f64 * PI * u2;
+    Vect(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Cosine distributed direction in the hemisphere around the z axis
+pub fn cosine_sample_hemisphere(u1: f64, u2: f64) -> Vect {
+    let r = u1.sqrt();
+    let phi = 2f64 * PI * u2;
+    Vect(r * phi.cos(), r * phi.sin(), (1f64 - u1).max(0f64).sqrt())
+}
+
+/// Density of cosine_sample_hemisphere, for a direction at cos_theta from
+/// the axis
+pub fn cosine_hemisphere_pdf(cos_theta: f64) -> f64 {
+    cos_theta.max(0f64) / PI
+}
+
+/// Uniformly distributed direction in the hemisphere around the z axis
+pub fn uniform_sample_hemisphere(u1: f64, u2: f64) -> Vect {
+    let z = u1;
+    let r = (1f64 - z * z).max(0f64).sqrt();
+    let phi = 2f64 * PI * u2;
+    Vect(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Uniformly distributed direction over the whole sphere
+pub fn uniform_sample_sphere(u1: f64, u2: f64) -> Vect {
+    let z = 1f64 - 2f64 * u1;
+    let r = (1f64 - z * z).max(0f64).sqrt();
+    let phi = 2f64 * PI * u2;
+    Vect(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Density of uniform_sample_hemisphere
+pub fn uniform_hemisphere_pdf() -> f64 {
+    1f64 / (2f64 * PI)
+}
+
+/// Piecewise constant distribution over [0, 1) with one bucket per
+/// entry of the function it was built from.
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Distribution1D {
+        let n = func.len();
+        let func: Vec<f64> = func.iter().map(|f| f.abs()).collect();
+        let mut cdf = vec![0f64; n + 1];
+        for i in 1..(n + 1) {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f64;
+        }
+        let integral = cdf[n];
+        if integral == 0f64 {
+            // Degenerate function, fall back to a uniform distribution
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Sample a continuous value in [0, 1). Returns the value, its pdf and
+    /// the index of the bucket it fell in.
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        // Last index such that cdf[i] <= u
+        let i = match self.cdf.partition_point(|c| *c <= u) {
+            0 => 0,
+            p => (p - 1).min(self.count() - 1),
+        };
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let du = if width > 0f64 {
+            (u - self.cdf[i]) / width
+        } else {
+            0f64
+        };
+        let x = (i as f64 + du) / self.count() as f64;
+        (x, self.pdf(i), i)
+    }
+
+    /// Density of bucket i with respect to the [0, 1) domain
+    pub fn pdf(&self, i: usize) -> f64 {
+        if self.integral == 0f64 {
+            1f64
+        } else {
+            self.func[i] / self.integral
+        }
+    }
+}
+
+/// Piecewise constant distribution over [0, 1)^2, sampled by first
+/// choosing a row from the marginal and then a column from that row.
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// func is given row by row, each row being `width` long
+    pub fn new(func: &[f64], width: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> =
+            func.chunks(width).map(Distribution1D::new).collect();
+        let marginal_func: Vec<f64> = conditional.iter().map(|d| d.integral).collect();
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&marginal_func),
+        }
+    }
+
+    /// Returns ((u, v), pdf) where u is the column and v the row coordinate
+    pub fn sample(&self, u1: f64, u2: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, row) = self.marginal.sample(u2);
+        let (u, pdf_u, _) = self.conditional[row].sample(u1);
+        ((u, v), pdf_u * pdf_v)
+    }
+}
+
+#[test]
+fn orthonormal_basis_test() {
+    for n in [
+        Vect(0.0, 0.0, 1.0),
+        Vect(0.0, 0.0, -1.0),
+        Vect(1.0, 2.0, 3.0).normalise(),
+        Vect(-0.3, 0.1, -0.2).normalise(),
+    ] {
+        let (t1, t2) = orthonormal_basis(&n);
+        assert!((t1.norm() - 1f64).abs() < 1e-12);
+        assert!((t2.norm() - 1f64).abs() < 1e-12);
+        assert!(t1.dot(&t2).abs() < 1e-12);
+        assert!(t1.dot(&n).abs() < 1e-12);
+        assert!(t2.dot(&n).abs() < 1e-12);
+    }
+}
+
+#[test]
+fn distribution_test() {
+    let d = Distribution1D::new(&[0f64, 1f64, 3f64]);
+    // Nothing should ever land in the empty bucket
+    assert_eq!(d.sample(0f64).2, 1);
+    assert_eq!(d.sample(0.2).2, 1);
+    assert_eq!(d.sample(0.9).2, 2);
+    assert!((d.pdf(2) - 2.25).abs() < 1e-12);
+
+    let d2 = Distribution2D::new(&[1f64, 1f64, 0f64, 2f64], 2);
+    let ((u, v), pdf) = d2.sample(0.5, 0.9);
+    assert!(u >= 0.5 && v >= 0.5);
+    // Bottom right cell holds 2/4 of the mass in 1/4 of the area
+    assert!((pdf - 2f64).abs() < 1e-12);
+}
@@ -1,27 +1,114 @@
-use crate::light::Pointlight;
+use crate::checkpoint;
+use crate::environment::{Constant, EnvironmentMap, Gradient};
+use crate::light::{DirectionalLight, Pointlight, SpotLight, Sunlight};
+use crate::medium::Medium;
 use crate::plane::Plane;
+use crate::principled::Principled;
+use crate::sampler::SamplerType;
+use crate::settings::{
+    AdaptiveSettings, Budget, CheckpointSettings, DebugMode, DenoiseSettings, Integrator,
+    ProgressiveSettings, RenderSettings,
+};
+use crate::sky::{sun_direction, Sky};
 use crate::sphere::Sphere;
+use crate::texture::{SurfaceMap, Texture};
 use crate::typedefs::{Material, Scene};
 use crate::vect::Vect;
+use crate::volume::{Volume, VoxelGrid};
 use serde::Deserialize;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{Error, ErrorKind};
+use std::io::Error;
+use std::sync::Arc;
+
+/// Samples per pixel for renders on a budget that don't give nrays
+const BUDGET_NRAYS: u32 = 1 << 16;
+/// Ambient occlusion rays per sample, and how far they reach, for scenes
+/// that don't give ao_rays and ao_distance. Rays that reach forever would
+/// make closed rooms black.
+const AO_RAYS: u32 = 16;
+const AO_DISTANCE: f64 = 1.0;
+/// Photons sent out, and how far from a point they count towards the light
+/// there, for photon mapping scenes that don't give photons and
+/// photon_radius
+const PHOTONS: u32 = 1_000_000;
+const PHOTON_RADIUS: f64 = 0.1;
+/// Height of white over black in bump maps without bump_strength, in scene
+/// units
+const BUMP_STRENGTH: f64 = 0.01;
+/// Keys whose values are files the scene loads, anywhere in the scene file
+const ASSET_KEYS: [&str; 5] = [
+    "path",
+    "density_grid",
+    "emission_grid",
+    "normal_map",
+    "bump_map",
+];
+
+/// The material keys, which every kind of object has
+#[derive(Deserialize)]
+struct MaterialLoader {
+    material: String,
+    colour: Option<[f64; 3]>,
+    ior: Option<f64>,
+    specular: Option<f64>,
+    shininess: Option<f64>,
+    scatter_radius: Option<[f64; 3]>,
+    metallic: Option<f64>,
+    roughness: Option<f64>,
+    clearcoat: Option<f64>,
+    sheen: Option<f64>,
+    transmission: Option<f64>,
+    emission: Option<[f64; 3]>,
+}
 
 #[derive(Deserialize)]
 struct SphereLoader {
     position: [f64; 3],
     radius: f64,
-    material: String,
-    colour: Option<[f64; 3]>,
+    #[serde(flatten)]
+    material: MaterialLoader,
+    medium: Option<MediumLoader>,
+    #[serde(flatten)]
+    surface_map: SurfaceMapLoader,
+}
+
+/// Normal or bump map of a sphere or plane
+#[derive(Deserialize)]
+struct SurfaceMapLoader {
+    normal_map: Option<String>,
+    bump_map: Option<String>,
+    bump_strength: Option<f64>,
+    map_size: Option<f64>,
+}
+
+/// The fog table, and the medium inside spheres and volumes
+#[derive(Deserialize)]
+struct MediumLoader {
+    sigma_a: f64,
+    sigma_s: f64,
+    g: Option<f64>,
+    emission: Option<[f64; 3]>,
+}
+
+#[derive(Deserialize)]
+struct VolumeLoader {
+    min: [f64; 3],
+    max: [f64; 3],
+    density_grid: Option<String>,
+    emission_grid: Option<String>,
+    #[serde(flatten)]
+    medium: MediumLoader,
 }
 
 #[derive(Deserialize)]
 struct PlaneLoader {
     point: [f64; 3],
     normal: [f64; 3],
-    material: String,
-    colour: Option<[f64; 3]>,
+    #[serde(flatten)]
+    material: MaterialLoader,
+    #[serde(flatten)]
+    surface_map: SurfaceMapLoader,
 }
 
 #[derive(Deserialize)]
@@ -30,56 +117,116 @@ struct PointlightLoader {
     intensity: f64,
 }
 
+#[derive(Deserialize)]
+struct DirectionalLightLoader {
+    direction: [f64; 3],
+    intensity: f64,
+}
+
+#[derive(Deserialize)]
+struct SpotLightLoader {
+    position: [f64; 3],
+    direction: [f64; 3],
+    intensity: f64,
+    inner_angle: f64,
+    outer_angle: f64,
+}
+
+#[derive(Deserialize)]
+struct BackgroundLoader {
+    #[serde(rename = "type")]
+    kind: String,
+    colour: Option<[f64; 3]>,
+    top: Option<[f64; 3]>,
+    bottom: Option<[f64; 3]>,
+    path: Option<String>,
+    intensity: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct SkyLoader {
+    sun_elevation: f64,
+    sun_azimuth: f64,
+    turbidity: Option<f64>,
+    intensity: Option<f64>,
+    sun_intensity: Option<f64>,
+    sun_angular_diameter: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct RenderLoader {
+    nrays: Option<u32>,
+    min_rays: Option<u32>,
+    max_error: Option<f64>,
+    heatmap: Option<String>,
+    aovs: Option<String>,
+    denoise: Option<String>,
+    denoise_iterations: Option<u32>,
+    denoise_sigma_colour: Option<f64>,
+    progressive: Option<bool>,
+    snapshot: Option<String>,
+    snapshot_passes: Option<u32>,
+    snapshot_seconds: Option<f64>,
+    checkpoint: Option<String>,
+    resume: Option<bool>,
+    time_limit: Option<f64>,
+    max_noise: Option<f64>,
+    integrator: Option<String>,
+    debug: Option<String>,
+    ao_rays: Option<u32>,
+    ao_distance: Option<f64>,
+    photons: Option<u32>,
+    photon_radius: Option<f64>,
+    depth: Option<u8>,
+    min_bounces: Option<u8>,
+    threads: Option<usize>,
+    seed: Option<u64>,
+    sampler: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct SceneLoader {
+    render: Option<RenderLoader>,
     sphere: Option<Vec<SphereLoader>>,
     plane: Option<Vec<PlaneLoader>>,
+    volume: Option<Vec<VolumeLoader>>,
     point_light: Option<Vec<PointlightLoader>>,
+    directional_light: Option<Vec<DirectionalLightLoader>>,
+    spot_light: Option<Vec<SpotLightLoader>>,
+    background: Option<BackgroundLoader>,
+    sky: Option<SkyLoader>,
+    fog: Option<MediumLoader>,
 }
 
-pub fn load_scene(filename: &str) -> Result<Scene, Error> {
+pub fn load_scene(filename: &str) -> Result<(Scene, RenderSettings), Error> {
     let mut f = File::open(filename)?;
-    let mut buffer = [0u8; 4096];
-    let n = f.read(&mut buffer)?;
     let mut s = String::new();
-    for i in 0..n {
-        s.push(buffer[i] as char);
-    }
-    let decoded: SceneLoader = toml::from_str(&s).unwrap();
-    let mut scene: Scene = (Vec::new(), Vec::new());
+    f.read_to_string(&mut s)?;
+    let decoded: SceneLoader = toml::from_str(&s).map_err(|e| Error::other(e.to_string()))?;
+    let mut scene: Scene = (
+        Vec::new(),
+        Vec::new(),
+        Box::new(Constant(Vect(50.0, 0.0, 0.0))),
+        None,
+    );
     match decoded.sphere {
         None => (),
         Some(spheres) => {
             for sphere_loader in spheres {
-                match &sphere_loader.material.as_str() {
-                    &"Lambertian" => match sphere_loader.colour {
-                        None => {
-                            return Err(Error::new(
-                                ErrorKind::Other,
-                                "Lambertian materials must also specify colour",
-                            ))
-                        }
-                        Some(c) => scene.0.push(Box::new(Sphere {
-                            pos: Vect(
-                                sphere_loader.position[0],
-                                sphere_loader.position[1],
-                                sphere_loader.position[2],
-                            ),
-                            radius: sphere_loader.radius,
-                            material: Material::Lambertian(Vect(c[0], c[1], c[2])),
-                        })),
+                scene.0.push(Box::new(Sphere {
+                    pos: Vect(
+                        sphere_loader.position[0],
+                        sphere_loader.position[1],
+                        sphere_loader.position[2],
+                    ),
+                    radius: sphere_loader.radius,
+                    material: load_material(&sphere_loader.material)?,
+                    medium: match &sphere_loader.medium {
+                        None => None,
+                        Some(medium_loader) => Some(load_medium(medium_loader)?),
                     },
-                    &"Mirror" => scene.0.push(Box::new(Sphere {
-                        pos: Vect(
-                            sphere_loader.position[0],
-                            sphere_loader.position[1],
-                            sphere_loader.position[2],
-                        ),
-                        radius: sphere_loader.radius,
-                        material: Material::Mirror,
-                    })),
-                    _ => return Err(Error::new(ErrorKind::Other, "Invalid material type")),
-                }
+                    surface_map: load_surface_map(&sphere_loader.surface_map)?,
+                }));
             }
         }
     }
@@ -87,30 +234,54 @@ pub fn load_scene(filename: &str) -> Result<Scene, Error> {
         None => (),
         Some(planes) => {
             for plane_loader in planes {
-                match &plane_loader.material.as_str() {
-                    &"Lambertian" => match plane_loader.colour {
-                        None => {
-                            return Err(Error::new(
-                                ErrorKind::Other,
-                                "Lambertian materials must also specify colour",
-                            ))
-                        }
-                        Some(c) => scene.0.push(Box::new(Plane {
-                            point: Vect(
-                                plane_loader.point[0],
-                                plane_loader.point[1],
-                                plane_loader.point[2],
-                            ),
-                            normal: Vect(
-                                plane_loader.normal[0],
-                                plane_loader.normal[1],
-                                plane_loader.normal[2],
-                            ),
-                            material: Material::Lambertian(Vect(c[0], c[1], c[2])),
-                        })),
-                    },
-                    _ => return Err(Error::new(ErrorKind::Other, "Invalid material type")),
+                scene.0.push(Box::new(Plane {
+                    point: Vect(
+                        plane_loader.point[0],
+                        plane_loader.point[1],
+                        plane_loader.point[2],
+                    ),
+                    normal: Vect(
+                        plane_loader.normal[0],
+                        plane_loader.normal[1],
+                        plane_loader.normal[2],
+                    ),
+                    material: load_material(&plane_loader.material)?,
+                    surface_map: load_surface_map(&plane_loader.surface_map)?,
+                }));
+            }
+        }
+    }
+    match decoded.volume {
+        None => (),
+        Some(volumes) => {
+            for volume_loader in volumes {
+                let min = Vect(
+                    volume_loader.min[0],
+                    volume_loader.min[1],
+                    volume_loader.min[2],
+                );
+                let max = Vect(
+                    volume_loader.max[0],
+                    volume_loader.max[1],
+                    volume_loader.max[2],
+                );
+                if min.0 >= max.0 || min.1 >= max.1 || min.2 >= max.2 {
+                    return Err(Error::other("Volumes must have min below max"));
+                }
+                let mut medium = load_medium(&volume_loader.medium)?;
+                if let Some(filename) = &volume_loader.density_grid {
+                    medium.density = Some(Arc::new(VoxelGrid::load(filename, min, max)?));
+                }
+                match &volume_loader.emission_grid {
+                    None => (),
+                    Some(_) if volume_loader.medium.emission.is_none() => {
+                        return Err(Error::other("emission_grid needs emission"))
+                    }
+                    Some(filename) => {
+                        medium.emission_grid = Some(Arc::new(VoxelGrid::load(filename, min, max)?))
+                    }
                 }
+                scene.0.push(Box::new(Volume { min, max, medium }));
             }
         }
     }
@@ -129,5 +300,454 @@ pub fn load_scene(filename: &str) -> Result<Scene, Error> {
             }
         }
     }
-    Ok(scene)
+    match decoded.directional_light {
+        None => (),
+        Some(directional_lights) => {
+            for directional_light_loader in directional_lights {
+                scene.1.push(Box::new(DirectionalLight {
+                    dir: Vect(
+                        directional_light_loader.direction[0],
+                        directional_light_loader.direction[1],
+                        directional_light_loader.direction[2],
+                    )
+                    .normalise(),
+                    intensity: directional_light_loader.intensity,
+                }));
+            }
+        }
+    }
+    match decoded.spot_light {
+        None => (),
+        Some(spot_lights) => {
+            for spot_light_loader in spot_lights {
+                if spot_light_loader.inner_angle > spot_light_loader.outer_angle {
+                    return Err(Error::other(
+                        "Spot light inner angle must not exceed the outer angle",
+                    ));
+                }
+                scene.1.push(Box::new(SpotLight {
+                    pos: Vect(
+                        spot_light_loader.position[0],
+                        spot_light_loader.position[1],
+                        spot_light_loader.position[2],
+                    ),
+                    dir: Vect(
+                        spot_light_loader.direction[0],
+                        spot_light_loader.direction[1],
+                        spot_light_loader.direction[2],
+                    )
+                    .normalise(),
+                    intensity: spot_light_loader.intensity,
+                    inner_angle: spot_light_loader.inner_angle.to_radians(),
+                    outer_angle: spot_light_loader.outer_angle.to_radians(),
+                }));
+            }
+        }
+    }
+    if decoded.background.is_some() && decoded.sky.is_some() {
+        return Err(Error::other(
+            "A scene can't have both a sky and a background",
+        ));
+    }
+    match decoded.background {
+        None => (),
+        Some(background_loader) => match background_loader.kind.as_str() {
+            "Constant" => match background_loader.colour {
+                None => {
+                    return Err(Error::other(
+                        "Constant backgrounds must also specify colour",
+                    ))
+                }
+                Some(c) => scene.2 = Box::new(Constant(Vect(c[0], c[1], c[2]))),
+            },
+            "Gradient" => match (background_loader.top, background_loader.bottom) {
+                (Some(t), Some(b)) => {
+                    scene.2 = Box::new(Gradient {
+                        top: Vect(t[0], t[1], t[2]),
+                        bottom: Vect(b[0], b[1], b[2]),
+                    })
+                }
+                _ => {
+                    return Err(Error::other(
+                        "Gradient backgrounds must specify top and bottom",
+                    ))
+                }
+            },
+            "EnvironmentMap" => match background_loader.path {
+                None => {
+                    return Err(Error::other(
+                        "Environment map backgrounds must specify path",
+                    ))
+                }
+                Some(path) => {
+                    scene.2 = Box::new(EnvironmentMap::load(
+                        &path,
+                        background_loader.intensity.unwrap_or(1f64),
+                    )?)
+                }
+            },
+            _ => return Err(Error::other("Invalid background type")),
+        },
+    }
+    match decoded.sky {
+        None => (),
+        Some(sky_loader) => {
+            let sun_dir = sun_direction(
+                sky_loader.sun_elevation.to_radians(),
+                sky_loader.sun_azimuth.to_radians(),
+            );
+            scene.2 = Box::new(Sky::new(
+                sun_dir,
+                sky_loader.turbidity.unwrap_or(3f64),
+                sky_loader.intensity.unwrap_or(1f64),
+            ));
+            match sky_loader.sun_intensity {
+                None => (),
+                Some(intensity) => scene.1.push(Box::new(Sunlight {
+                    to_sun: sun_dir,
+                    intensity,
+                    angular_radius: sky_loader.sun_angular_diameter.unwrap_or(0.53).to_radians()
+                        / 2f64,
+                })),
+            }
+        }
+    }
+    match decoded.fog {
+        None => (),
+        Some(fog_loader) => scene.3 = Some(load_medium(&fog_loader)?),
+    }
+    let mut settings = RenderSettings::default();
+    match decoded.render {
+        None => (),
+        Some(render_loader) => {
+            if render_loader.time_limit.is_some() || render_loader.max_noise.is_some() {
+                settings.budget = Some(Budget {
+                    seconds: render_loader.time_limit,
+                    max_noise: render_loader.max_noise,
+                });
+                // The budget is what stops the render unless nrays is given
+                settings.nrays = BUDGET_NRAYS;
+            }
+            settings.nrays = render_loader.nrays.unwrap_or(settings.nrays);
+            match (render_loader.min_rays, render_loader.max_error) {
+                (None, None) => (),
+                (Some(min_rays), Some(max_error)) => {
+                    if min_rays < 2 || min_rays > settings.nrays {
+                        return Err(Error::other("min_rays must be between 2 and nrays"));
+                    }
+                    settings.adaptive = Some(AdaptiveSettings {
+                        min_rays,
+                        max_error,
+                    })
+                }
+                _ => {
+                    return Err(Error::other(
+                        "Adaptive sampling needs both min_rays and max_error",
+                    ))
+                }
+            }
+            settings.heatmap = render_loader.heatmap;
+            settings.aovs = render_loader.aovs;
+            match render_loader.denoise {
+                None => {
+                    if render_loader.denoise_iterations.is_some()
+                        || render_loader.denoise_sigma_colour.is_some()
+                    {
+                        return Err(Error::other("Denoiser settings need denoise"));
+                    }
+                }
+                Some(output) => {
+                    if render_loader
+                        .denoise_sigma_colour
+                        .is_some_and(|sigma| sigma <= 0f64)
+                    {
+                        return Err(Error::other("denoise_sigma_colour must be positive"));
+                    }
+                    let defaults = DenoiseSettings::default();
+                    settings.denoise = Some(DenoiseSettings {
+                        output,
+                        iterations: render_loader
+                            .denoise_iterations
+                            .unwrap_or(defaults.iterations),
+                        sigma_colour: render_loader
+                            .denoise_sigma_colour
+                            .unwrap_or(defaults.sigma_colour),
+                        ..defaults
+                    })
+                }
+            }
+            match render_loader.progressive {
+                Some(true) => {
+                    if render_loader.snapshot_passes == Some(0) {
+                        return Err(Error::other("snapshot_passes must be at least 1"));
+                    }
+                    if render_loader
+                        .snapshot_seconds
+                        .is_some_and(|secs| secs <= 0f64)
+                    {
+                        return Err(Error::other("snapshot_seconds must be positive"));
+                    }
+                    // Without a schedule, save after every pass
+                    let every_pass = render_loader.snapshot_passes.is_none()
+                        && render_loader.snapshot_seconds.is_none();
+                    settings.progressive = Some(ProgressiveSettings {
+                        snapshot: render_loader
+                            .snapshot
+                            .unwrap_or_else(|| "snapshot.png".to_string()),
+                        snapshot_passes: if every_pass {
+                            Some(1)
+                        } else {
+                            render_loader.snapshot_passes
+                        },
+                        snapshot_seconds: render_loader.snapshot_seconds,
+                    })
+                }
+                _ => {
+                    if render_loader.snapshot.is_some()
+                        || render_loader.snapshot_passes.is_some()
+                        || render_loader.snapshot_seconds.is_some()
+                    {
+                        return Err(Error::other("Snapshots need progressive = true"));
+                    }
+                }
+            }
+            match (
+                render_loader.integrator.as_deref(),
+                render_loader.debug.as_deref(),
+            ) {
+                (None | Some("Path"), None) => (),
+                (Some("Bidirectional"), None) => settings.integrator = Integrator::Bidirectional,
+                (Some("Whitted"), None) => settings.integrator = Integrator::Whitted,
+                (Some("Debug"), Some(mode)) => {
+                    settings.integrator = Integrator::Debug(match mode {
+                        "Unlit" => DebugMode::Unlit,
+                        "Normals" => DebugMode::Normals,
+                        "Uv" => DebugMode::Uv,
+                        "FacingRatio" => DebugMode::FacingRatio,
+                        "NanInf" => DebugMode::NanInf,
+                        "Depth" => DebugMode::Depth,
+                        _ => return Err(Error::other("Invalid debug mode")),
+                    })
+                }
+                (Some("Debug"), None) => {
+                    return Err(Error::other("The debug integrator needs a debug mode"))
+                }
+                (_, Some(_)) => {
+                    return Err(Error::other("Debug modes need integrator = \"Debug\""))
+                }
+                (Some("AmbientOcclusion"), None) => {
+                    let rays = render_loader.ao_rays.unwrap_or(AO_RAYS);
+                    let max_distance = render_loader.ao_distance.unwrap_or(AO_DISTANCE);
+                    if rays == 0 || max_distance <= 0f64 {
+                        return Err(Error::other("ao_rays and ao_distance must be positive"));
+                    }
+                    settings.integrator = Integrator::AmbientOcclusion { rays, max_distance }
+                }
+                (Some("PhotonMapping"), None) => {
+                    let photons = render_loader.photons.unwrap_or(PHOTONS);
+                    let radius = render_loader.photon_radius.unwrap_or(PHOTON_RADIUS);
+                    if photons == 0 || radius <= 0f64 {
+                        return Err(Error::other("photons and photon_radius must be positive"));
+                    }
+                    settings.integrator = Integrator::PhotonMapping { photons, radius }
+                }
+                _ => return Err(Error::other("Invalid integrator")),
+            }
+            if !matches!(settings.integrator, Integrator::AmbientOcclusion { .. })
+                && (render_loader.ao_rays.is_some() || render_loader.ao_distance.is_some())
+            {
+                return Err(Error::other(
+                    "ao_rays and ao_distance need integrator = \"AmbientOcclusion\"",
+                ));
+            }
+            if !matches!(settings.integrator, Integrator::PhotonMapping { .. })
+                && (render_loader.photons.is_some() || render_loader.photon_radius.is_some())
+            {
+                return Err(Error::other(
+                    "photons and photon_radius need integrator = \"PhotonMapping\"",
+                ));
+            }
+            settings.depth = render_loader.depth.unwrap_or(settings.depth);
+            settings.min_bounces = render_loader.min_bounces.unwrap_or(settings.min_bounces);
+            settings.threads = render_loader.threads.unwrap_or(settings.threads);
+            settings.seed = render_loader.seed.unwrap_or(settings.seed);
+            match render_loader.sampler.as_deref() {
+                None => (),
+                Some("Independent") => settings.sampler = SamplerType::Independent,
+                Some("Stratified") => settings.sampler = SamplerType::Stratified,
+                Some("Halton") => settings.sampler = SamplerType::Halton,
+                Some("Sobol") => settings.sampler = SamplerType::Sobol,
+                Some(_) => return Err(Error::other("Invalid sampler type")),
+            }
+            match render_loader.checkpoint {
+                None => {
+                    if render_loader.resume.is_some() {
+                        return Err(Error::other("Resuming needs a checkpoint file"));
+                    }
+                }
+                Some(filename) => {
+                    // Everything but the [render] table, plus the contents
+                    // of the files it loads and the settings that change
+                    // what a sample looks like
+                    let mut table: toml::Table = s
+                        .parse()
+                        .map_err(|e: toml::de::Error| Error::other(e.to_string()))?;
+                    table.remove("render");
+                    let mut assets = Vec::new();
+                    asset_hashes(&table, &mut assets)?;
+                    let key = format!(
+                        "{}\n{:?}\n{} {} {:?} {:?}",
+                        table,
+                        assets,
+                        settings.depth,
+                        settings.min_bounces,
+                        settings.sampler,
+                        settings.integrator
+                    );
+                    settings.checkpoint = Some(CheckpointSettings {
+                        filename,
+                        resume: render_loader.resume.unwrap_or(false),
+                        scene_hash: checkpoint::hash(key.as_bytes()),
+                    })
+                }
+            }
+        }
+    }
+    Ok((scene, settings))
+}
+
+/// Hashes of the files named under ASSET_KEYS in table and the tables in it
+fn asset_hashes(table: &toml::Table, hashes: &mut Vec<u64>) -> Result<(), Error> {
+    for (key, value) in table {
+        match value {
+            toml::Value::String(path) if ASSET_KEYS.contains(&key.as_str()) => {
+                hashes.push(checkpoint::hash(&std::fs::read(path)?))
+            }
+            toml::Value::Table(table) => asset_hashes(table, hashes)?,
+            toml::Value::Array(values) => {
+                for value in values {
+                    if let toml::Value::Table(table) = value {
+                        asset_hashes(table, hashes)?;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn load_material(loader: &MaterialLoader) -> Result<Material, Error> {
+    let colour = loader.colour.map(|c| Vect(c[0], c[1], c[2]));
+    match loader.material.as_str() {
+        "Lambertian" => match colour {
+            None => Err(Error::other(
+                "Lambertian materials must also specify colour",
+            )),
+            Some(colour) => Ok(Material::Lambertian(colour)),
+        },
+        "Mirror" => Ok(Material::Mirror),
+        "Interface" => Ok(Material::Interface),
+        "Glass" => match loader.ior {
+            Some(ior) if ior > 0f64 && ior.is_finite() => Ok(Material::Glass(ior)),
+            _ => Err(Error::other(
+                "Glass materials must also specify a positive ior",
+            )),
+        },
+        "Phong" => match (colour, loader.specular, loader.shininess) {
+            (Some(colour), Some(specular), Some(shininess)) => {
+                Ok(Material::Phong(colour, specular, shininess))
+            }
+            _ => Err(Error::other(
+                "Phong materials must also specify colour, specular and shininess",
+            )),
+        },
+        "Subsurface" => match (colour, loader.scatter_radius) {
+            (Some(colour), Some([r, g, b])) if r > 0f64 && g > 0f64 && b > 0f64 => {
+                Ok(Material::Subsurface(colour, Vect(r, g, b)))
+            }
+            _ => Err(Error::other(
+                "Subsurface materials must also specify colour and a positive scatter_radius",
+            )),
+        },
+        "Principled" => {
+            let colour = colour
+                .ok_or_else(|| Error::other("Principled materials must also specify colour"))?;
+            let principled = Principled {
+                base_colour: colour,
+                metallic: loader.metallic.unwrap_or(0f64),
+                roughness: loader.roughness.unwrap_or(0.5),
+                specular: loader.specular.unwrap_or(0.5),
+                clearcoat: loader.clearcoat.unwrap_or(0f64),
+                sheen: loader.sheen.unwrap_or(0f64),
+                transmission: loader.transmission.unwrap_or(0f64),
+                ior: loader.ior.unwrap_or(1.5),
+                emission: loader
+                    .emission
+                    .map_or(Vect(0.0, 0.0, 0.0), |e| Vect(e[0], e[1], e[2])),
+            };
+            let fractions = [
+                principled.metallic,
+                principled.roughness,
+                principled.specular,
+                principled.clearcoat,
+                principled.sheen,
+                principled.transmission,
+            ];
+            if fractions.iter().any(|f| !(0f64..=1f64).contains(f)) {
+                return Err(Error::other(
+                    "metallic, roughness, specular, clearcoat, sheen and transmission must be between 0 and 1",
+                ));
+            }
+            if !(principled.ior > 0f64 && principled.ior.is_finite()) {
+                return Err(Error::other("ior must be positive"));
+            }
+            Ok(Material::Principled(principled))
+        }
+        _ => Err(Error::other("Invalid material type")),
+    }
+}
+
+fn load_medium(loader: &MediumLoader) -> Result<Medium, Error> {
+    let g = loader.g.unwrap_or(0f64);
+    if loader.sigma_a < 0f64 || loader.sigma_s < 0f64 {
+        return Err(Error::other("sigma_a and sigma_s can't be negative"));
+    }
+    if g <= -1f64 || g >= 1f64 {
+        return Err(Error::other("g must be between -1 and 1"));
+    }
+    Ok(Medium {
+        sigma_a: loader.sigma_a,
+        sigma_s: loader.sigma_s,
+        g,
+        density: None,
+        emission: loader
+            .emission
+            .map_or(Vect(0.0, 0.0, 0.0), |e| Vect(e[0], e[1], e[2])),
+        emission_grid: None,
+    })
+}
+
+fn load_surface_map(loader: &SurfaceMapLoader) -> Result<Option<SurfaceMap>, Error> {
+    let size = loader.map_size.unwrap_or(1f64);
+    if size <= 0f64 {
+        return Err(Error::other("map_size must be positive"));
+    }
+    match (&loader.normal_map, &loader.bump_map) {
+        (Some(_), Some(_)) => Err(Error::other(
+            "normal_map and bump_map can't be used together",
+        )),
+        (Some(_), None) if loader.bump_strength.is_some() => {
+            Err(Error::other("bump_strength needs bump_map"))
+        }
+        (Some(filename), None) => Ok(Some(SurfaceMap::Normal(Texture::load(filename, size)?))),
+        (None, Some(filename)) => Ok(Some(SurfaceMap::Bump(
+            Texture::load(filename, size)?,
+            loader.bump_strength.unwrap_or(BUMP_STRENGTH),
+        ))),
+        (None, None) if loader.bump_strength.is_some() || loader.map_size.is_some() => Err(
+            Error::other("bump_strength and map_size need normal_map or bump_map"),
+        ),
+        (None, None) => Ok(None),
+    }
 }
@@ -0,0 +1,167 @@
+//! Everything about how a scene is rendered, as opposed to what's in it.
+//! Loaded from the optional [render] table of the scene file, anything left
+//! out falls back to the defaults in main.rs.
+
+use crate::photon::PhotonMap;
+use crate::sampler::SamplerType;
+use std::sync::Arc;
+
+/// How the light arriving along a camera ray gets worked out
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Integrator {
+    /// Physically based path tracing
+    Path,
+    /// Path tracing from the camera and the lights at once
+    Bidirectional,
+    /// Path tracing, with caustics from photons sent out from the lights
+    /// before the render. Photons within radius of a point count towards
+    /// the light there.
+    PhotonMapping { photons: u32, radius: f64 },
+    /// Deterministic Whitted-style ray tracing: hard shadows from the
+    /// lights, perfect reflection and refraction, and nothing else
+    Whitted,
+    /// Shows something about the scene other than its lighting
+    Debug(DebugMode),
+    /// Clay render: the fraction of the rays sent off over the hemisphere
+    /// of the first hit that don't hit anything within max_distance
+    AmbientOcclusion { rays: u32, max_distance: f64 },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DebugMode {
+    /// Path traced, but surfaces that get no direct light at all in magenta
+    Unlit,
+    /// Surface normals, from -1 to 1 mapped to black to full colour
+    Normals,
+    /// Surface coordinates in red and green, repeating every unit
+    Uv,
+    /// Cosine between the surface normal and the camera ray, in grey
+    FacingRatio,
+    /// Path traced in grey, with NaN samples in magenta and infinite ones
+    /// in cyan
+    NanInf,
+    /// Distance to the first hit, white up close and fading with distance
+    Depth,
+}
+
+/// Adaptive sampling takes at least min_rays samples of every pixel, and
+/// then keeps going until the relative standard error of the pixel's mean
+/// brightness drops under max_error, or nrays is reached.
+#[derive(Clone)]
+pub struct AdaptiveSettings {
+    pub min_rays: u32,
+    pub max_error: f64,
+}
+
+/// Progressive rendering goes over the whole image one sample per pixel at
+/// a time, saving the image so far to snapshot every snapshot_passes passes
+/// or snapshot_seconds seconds, whichever comes first.
+#[derive(Clone)]
+pub struct ProgressiveSettings {
+    pub snapshot: String,
+    pub snapshot_passes: Option<u32>,
+    pub snapshot_seconds: Option<f64>,
+}
+
+/// Limits on how long a render carries on, on top of nrays. A render on a
+/// budget goes over the image one sample per pixel at a time, so that it's
+/// evenly sampled whenever it stops.
+#[derive(Clone)]
+pub struct Budget {
+    /// Stop after this many seconds
+    pub seconds: Option<f64>,
+    /// Stop once the average relative error of the pixels is below this
+    pub max_noise: Option<f64>,
+}
+
+/// The denoiser runs after the render, and saves its result to output.
+/// Each sigma is how big a difference in that feature between two pixels
+/// has to be for them to stop being blurred together.
+#[derive(Clone)]
+pub struct DenoiseSettings {
+    pub output: String,
+    pub iterations: u32,
+    /// Of the displayed colours, scaled to [0, 1]. Halves every iteration.
+    pub sigma_colour: f64,
+    pub sigma_normal: f64,
+    pub sigma_albedo: f64,
+    /// Of the depths relative to the closer one
+    pub sigma_depth: f64,
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> DenoiseSettings {
+        DenoiseSettings {
+            output: "denoised.png".to_string(),
+            iterations: 5,
+            sigma_colour: 0.5,
+            sigma_normal: 0.3,
+            sigma_albedo: 0.1,
+            sigma_depth: 0.1,
+        }
+    }
+}
+
+/// Where the render so far gets saved, at every snapshot and at the end,
+/// so that a later run can carry on with it.
+#[derive(Clone)]
+pub struct CheckpointSettings {
+    pub filename: String,
+    /// Start from the checkpoint file if there is one
+    pub resume: bool,
+    /// Hash of the scene, the files it loads and the settings the samples
+    /// depend on. Only checkpoints with the same hash can be resumed.
+    pub scene_hash: u64,
+}
+
+#[derive(Clone)]
+pub struct RenderSettings {
+    /// Samples per pixel, the maximum when sampling adaptively
+    pub nrays: u32,
+    pub adaptive: Option<AdaptiveSettings>,
+    pub progressive: Option<ProgressiveSettings>,
+    pub checkpoint: Option<CheckpointSettings>,
+    pub budget: Option<Budget>,
+    /// Where to save an image of the number of samples taken per pixel
+    pub heatmap: Option<String>,
+    /// Prefix of the files to save the AOVs to, if any
+    pub aovs: Option<String>,
+    pub denoise: Option<DenoiseSettings>,
+    pub integrator: Integrator,
+    /// Caustic photons for the path tracer to add the light of, sent out
+    /// at the start of a photon mapping render
+    pub photon_map: Option<Arc<PhotonMap>>,
+    /// Bounces after which paths are cut off, as a safety net for when
+    /// Russian roulette doesn't end them first
+    pub depth: u8,
+    /// Paths may be cut short by Russian roulette from this bounce on
+    pub min_bounces: u8,
+    /// Number of worker threads
+    pub threads: usize,
+    /// Renders with the same seed and settings are identical
+    pub seed: u64,
+    /// Where the random numbers come from
+    pub sampler: SamplerType,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            nrays: crate::NRAYS,
+            adaptive: None,
+            progressive: None,
+            checkpoint: None,
+            budget: None,
+            heatmap: None,
+            aovs: None,
+            denoise: None,
+            integrator: Integrator::Path,
+            photon_map: None,
+            depth: crate::DEPTH,
+            min_bounces: 3,
+            threads: crate::N_THREADS,
+            seed: 0,
+            sampler: SamplerType::Independent,
+        }
+    }
+}
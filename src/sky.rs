@@ -0,0 +1,130 @@
+//! Analytic daylight sky from Preetham, Shirley and Smits 1999,
+//! "A Practical Analytic Model for Daylight". The sky is only defined above
+//! the horizon, below it we return black and let the ground plane of the
+//! scene take over.
+
+use crate::environment::Environment;
+use crate::vect::*;
+use std::f64::consts::PI;
+
+pub struct Sky {
+    /// Unit vector pointing towards the sun
+    sun_dir: Vect,
+    /// Luminance (Y) and chromaticity (x, y) at the zenith
+    zenith: [f64; 3],
+    /// Perez distribution coefficients A to E for Y, x and y
+    perez: [[f64; 5]; 3],
+    /// Perez function evaluated at the zenith, the normalisation constant
+    perez_zenith: [f64; 3],
+    /// Multiplier applied to the sky radiance
+    intensity: f64,
+}
+
+/// Unit vector towards the sun. Elevation is measured from the horizon,
+/// azimuth from the +z axis towards +x, both in radians.
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vect {
+    Vect(
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+        elevation.cos() * azimuth.cos(),
+    )
+}
+
+fn perez(coeffs: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coeffs;
+    (1f64 + a * (b / cos_theta).exp())
+        * (1f64 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+}
+
+impl Sky {
+    pub fn new(sun_dir: Vect, turbidity: f64, intensity: f64) -> Sky {
+        let t = turbidity;
+        let sun_dir = sun_dir.normalise();
+        // Sun angle from the zenith. The model breaks down once the sun is
+        // below the horizon so we keep it just above.
+        let theta_s = sun_dir.1.clamp(0.001, 1f64).acos();
+        let chi = (4f64 / 9f64 - t / 120f64) * (PI - 2f64 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (t1, t2, t3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1)
+            + t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394)
+            + (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+        let zenith_yc = t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1)
+            + t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516)
+            + (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+        let perez_coeffs = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        let perez_zenith = [
+            perez(&perez_coeffs[0], 1f64, theta_s),
+            perez(&perez_coeffs[1], 1f64, theta_s),
+            perez(&perez_coeffs[2], 1f64, theta_s),
+        ];
+        Sky {
+            sun_dir,
+            zenith: [zenith_y, zenith_x, zenith_yc],
+            perez: perez_coeffs,
+            perez_zenith,
+            intensity,
+        }
+    }
+}
+
+impl Environment for Sky {
+    fn radiance(&self, dir: &Vect) -> Vect {
+        if dir.1 <= 0f64 {
+            return zero();
+        }
+        // Keep away from the horizon where 1 / cos_theta blows up
+        let cos_theta = dir.1.max(0.01);
+        let gamma = dir.dot(&self.sun_dir).clamp(-1f64, 1f64).acos();
+        let [lum, x, y] = [0, 1, 2].map(|i| {
+            self.zenith[i] * perez(&self.perez[i], cos_theta, gamma) / self.perez_zenith[i]
+        });
+        if y <= 0f64 {
+            return zero();
+        }
+        // xyY to XYZ to linear sRGB
+        let cx = x / y * lum;
+        let cz = (1f64 - x - y) / y * lum;
+        Vect(
+            3.2406 * cx - 1.5372 * lum - 0.4986 * cz,
+            -0.9689 * cx + 1.8758 * lum + 0.0415 * cz,
+            0.0557 * cx - 0.2040 * lum + 1.0570 * cz,
+        )
+        .scalar_mul(&self.intensity)
+    }
+}
+
+#[test]
+fn sky_test() {
+    let sky = Sky::new(sun_direction(PI / 6f64, 0f64), 3f64, 1f64);
+    assert_eq!(sky.radiance(&Vect(0.0, -1.0, 0.0)), zero());
+    // Brighter towards the sun than away from it
+    let towards = sky.radiance(&sun_direction(PI / 5f64, 0f64));
+    let away = sky.radiance(&sun_direction(PI / 5f64, PI));
+    assert!(towards.1 > away.1);
+    // Clear skies are blue at the zenith
+    let Vect(r, _, b) = sky.radiance(&Vect(0.0, 1.0, 0.0));
+    assert!(b > r && r > 0f64);
+}
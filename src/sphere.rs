@@ -1,12 +1,45 @@
 use crate::geometry::*;
+use crate::medium::Medium;
 use crate::ray::Ray;
+use crate::sampling::orthonormal_basis;
+use crate::texture::SurfaceMap;
 use crate::typedefs::*;
 use crate::vect::*;
+use std::f64::consts::PI;
 
 pub struct Sphere {
     pub pos: Vect,
     pub radius: f64,
     pub material: Material,
+    pub medium: Option<Medium>,
+    pub surface_map: Option<SurfaceMap>,
+}
+
+impl Sphere {
+    /// Intersection at pos on the surface. u goes around the equator from
+    /// the +x axis and v from the top of the sphere to the bottom.
+    fn hit(&self, pos: Vect) -> Intersection {
+        let normal = pos.sub(&self.pos).normalise();
+        // Around the equator, or anywhere along the surface at the poles
+        let around = Vect(-normal.2, 0f64, normal.0);
+        let tangent = if around.norm() > 0f64 {
+            around.normalise()
+        } else {
+            orthonormal_basis(&normal).0
+        };
+        Intersection {
+            pos,
+            normal,
+            shading_normal: normal,
+            tangent,
+            uv: (
+                0.5 + normal.2.atan2(normal.0) / (2f64 * PI),
+                normal.1.clamp(-1f64, 1f64).acos() / PI,
+            ),
+            // Around a circle of latitude, and half way round the sphere
+            uv_scale: (2f64 * PI * self.radius * around.norm(), PI * self.radius),
+        }
+    }
 }
 
 impl Geometry for Sphere {
@@ -16,53 +49,43 @@ impl Geometry for Sphere {
         let ocn = rpos.sub(&self.pos).norm();
         let discr = p * p - (ocn * ocn - self.radius * self.radius);
         if discr < 0f64 {
-            return Intersection {
-                pos: zero(),
-                normal: zero(),
-            };
+            return Intersection::miss();
         }
         if discr == 0f64 {
             let sol = -p;
             if sol >= 0f64 {
                 let int_pos = rpos.add(&rdir.scalar_mul(&sol));
-                return Intersection {
-                    pos: int_pos,
-                    normal: int_pos.sub(&self.pos).normalise(),
-                };
+                return self.hit(int_pos);
             }
-            return Intersection {
-                pos: zero(),
-                normal: zero(),
-            };
+            return Intersection::miss();
         }
         let sol1 = -p - discr.sqrt();
         let sol2 = -p + discr.sqrt();
         if sol1 >= 0f64 {
             //Both solutions in front of ray origin
             let int_pos = rpos.add(&rdir.scalar_mul(&sol1));
-            return Intersection {
-                pos: int_pos,
-                normal: int_pos.sub(&self.pos).normalise(),
-            };
+            return self.hit(int_pos);
         }
         if sol2 >= 0f64 {
             //Only second solution in front, ray origin inside the sphere
             let int_pos = rpos.add(&rdir.scalar_mul(&sol2));
-            return Intersection {
-                pos: int_pos,
-                normal: int_pos.sub(&self.pos).normalise(),
-            };
+            return self.hit(int_pos);
         }
         // Both solutions behind the ray origin
-        return Intersection {
-            pos: zero(),
-            normal: zero(),
-        };
+        Intersection::miss()
     }
 
     fn get_material(&self) -> Material {
         self.material
     }
+
+    fn get_medium(&self) -> Option<&Medium> {
+        self.medium.as_ref()
+    }
+
+    fn get_surface_map(&self) -> Option<&SurfaceMap> {
+        self.surface_map.as_ref()
+    }
 }
 
 #[test]
@@ -73,7 +96,16 @@ fn sphere_intersection_test() {
         pos: Vect(10f64, 0f64, 0f64),
         radius: 5f64,
         material: Material::Lambertian(zero()),
+        medium: None,
+        surface_map: None,
     };
     assert_ne!(s.intersect(&r1).normal, zero());
+    // Hit on the equator
+    assert!((s.intersect(&r1).uv.1 - 0.5).abs() < 1e-12);
+    // u goes around the equator, the way the tangent points
+    let hit = s.intersect(&r1);
+    let ahead = s.intersect(&Ray(zero(), Vect(1f64, 0f64, 0.01).normalise()));
+    assert!(hit.tangent.dot(&hit.normal).abs() < 1e-12);
+    assert!(ahead.pos.sub(&hit.pos).dot(&hit.tangent) * (ahead.uv.0 - hit.uv.0) > 0f64);
     assert_eq!(s.intersect(&r2).normal, zero());
 }
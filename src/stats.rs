@@ -0,0 +1,48 @@
+//! Counters for the work done by a render, gathered per pixel and summed up
+//! as the pixels come in.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct RenderStats {
+    /// Paths started from the camera, one per sample
+    pub camera_rays: u64,
+    /// Every ray followed along the paths, camera rays included
+    pub rays: u64,
+    /// Rays testing whether a light or the environment is visible
+    pub shadow_rays: u64,
+    /// Rays followed sending out photons before the render
+    pub photon_rays: u64,
+}
+
+impl RenderStats {
+    pub fn add(&mut self, other: &RenderStats) {
+        self.camera_rays += other.camera_rays;
+        self.rays += other.rays;
+        self.shadow_rays += other.shadow_rays;
+        self.photon_rays += other.photon_rays;
+    }
+
+    /// Rays of every kind
+    pub fn total_rays(&self) -> u64 {
+        self.rays + self.shadow_rays + self.photon_rays
+    }
+
+    /// Average number of rays per path
+    pub fn average_path_length(&self) -> f64 {
+        if self.camera_rays == 0 {
+            return 0f64;
+        }
+        self.rays as f64 / self.camera_rays as f64
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "total rays: {}", self.total_rays())?;
+        writeln!(f, "  path rays: {}", self.rays)?;
+        writeln!(f, "  shadow rays: {}", self.shadow_rays)?;
+        writeln!(f, "  photon rays: {}", self.photon_rays)?;
+        write!(f, "average path length: {:.2}", self.average_path_length())
+    }
+}
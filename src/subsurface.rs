@@ -0,0 +1,170 @@
+//! Subsurface scattering by random walks, after Chiang et al.'s "Practical
+//! and Controllable Subsurface Scattering for Production Path Tracing".
+//! Light going into a subsurface material bounces around inside the object
+//! as if it were filled with a medium, until it finds its way out again
+//! somewhere else on the surface. The medium is picked so that a thick slab
+//! of it looks the colour of the material, with light getting about the
+//! scattering radius into it, separately for each colour. Only the object
+//! itself counts as the way out, so nothing should be put inside it.
+
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::*;
+use crate::stats::RenderStats;
+use crate::typedefs::{Intersection, Scene};
+use crate::vect::*;
+
+/// Light scattering more times than this inside an object is dropped
+const MAX_STEPS: u32 = 256;
+
+/// Where light coming in at entry on the surface of object leaves it again,
+/// and the fraction of each colour that makes it there. The light goes in
+/// and comes back out diffusely. None if it gets lost inside.
+pub fn random_walk(
+    scene: &Scene,
+    object: usize,
+    entry: &Intersection,
+    colour: &Vect,
+    radius: &Vect,
+    sampler: &mut dyn Sampler,
+    stats: &mut RenderStats,
+) -> Option<(Intersection, Vect)> {
+    let (sigma_t, albedo) = coefficients(colour, radius);
+    let (u1, u2) = sampler.get_2d();
+    let inwards = entry.normal.scalar_mul(&-1f64);
+    let mut ray = Ray::leaving(
+        entry,
+        local_to_world(&cosine_sample_hemisphere(u1, u2), &inwards),
+    );
+    let mut weight = Vect(1.0, 1.0, 1.0);
+    for _ in 0..MAX_STEPS {
+        stats.rays += 1;
+        let hit = scene.0[object].intersect(&ray);
+        let dist = if hit.normal == zero() {
+            f64::INFINITY
+        } else {
+            hit.pos.sub(&ray.0).norm()
+        };
+        // Distances get sampled for one colour, picked in proportion to how
+        // much of it is left, and weighted by how likely they were to be
+        // picked for any of them
+        let (u1, u2) = sampler.get_2d();
+        let total = weight.0 + weight.1 + weight.2;
+        if total <= 0f64 {
+            return None;
+        }
+        let channel = if u1 * total < weight.0 {
+            sigma_t.0
+        } else if u1 * total < weight.0 + weight.1 {
+            sigma_t.1
+        } else {
+            sigma_t.2
+        };
+        let t = -(1f64 - u2).ln() / channel;
+        if t >= dist {
+            let transmittance = exp(&sigma_t.scalar_mul(&-dist));
+            let pdf = weight.dot(&transmittance) / total;
+            // Surfaces like planes turn their normal towards the ray
+            let normal = if hit.normal.dot(&ray.1) < 0f64 {
+                hit.normal.scalar_mul(&-1f64)
+            } else {
+                hit.normal
+            };
+            return Some((
+                Intersection {
+                    pos: hit.pos.add(&normal.scalar_mul(&crate::EPSILON)),
+                    normal,
+                    shading_normal: normal,
+                    uv: hit.uv,
+                    uv_scale: hit.uv_scale,
+                    tangent: hit.tangent,
+                },
+                weight
+                    .pointwise_mul(&transmittance)
+                    .scalar_mul(&(1f64 / pdf)),
+            ));
+        }
+        let density = sigma_t.pointwise_mul(&exp(&sigma_t.scalar_mul(&-t)));
+        let pdf = weight.dot(&density) / total;
+        weight = weight
+            .pointwise_mul(&albedo.pointwise_mul(&density))
+            .scalar_mul(&(1f64 / pdf));
+        let (u1, u2) = sampler.get_2d();
+        ray = Ray(
+            ray.0.add(&ray.1.scalar_mul(&t)),
+            uniform_sample_sphere(u1, u2),
+        );
+    }
+    None
+}
+
+/// Extinction and single scattering albedo of the medium inside a material
+/// of the given colour and scattering radius, fit by Chiang et al. for
+/// isotropic scattering
+fn coefficients(colour: &Vect, radius: &Vect) -> (Vect, Vect) {
+    let channel = |a: f64, d: f64| {
+        let a = a.clamp(0f64, 0.999);
+        let albedo = 1f64 - (a * (-5.09406 + a * (2.61188 - a * 4.31805))).exp();
+        let s = 1.9 - a + 3.5 * (a - 0.8) * (a - 0.8);
+        (1f64 / (d * s).max(1e-9), albedo)
+    };
+    let (r, g, b) = (
+        channel(colour.0, radius.0),
+        channel(colour.1, radius.1),
+        channel(colour.2, radius.2),
+    );
+    (Vect(r.0, g.0, b.0), Vect(r.1, g.1, b.1))
+}
+
+fn exp(v: &Vect) -> Vect {
+    Vect(v.0.exp(), v.1.exp(), v.2.exp())
+}
+
+#[test]
+fn slab_colour_test() {
+    // A thick slab of subsurface material under a sky getting darker
+    // towards the horizon looks its colour times the 5/6 of white the sky
+    // lights it with, whatever the scattering radius of each colour
+    use crate::environment::Gradient;
+    use crate::plane::Plane;
+    use crate::sampler;
+    use crate::settings::RenderSettings;
+    use crate::typedefs::Material;
+    let colour = Vect(0.2, 0.5, 0.8);
+    let scene: Scene = (
+        vec![Box::new(Plane {
+            point: zero(),
+            normal: Vect(0.0, 1.0, 0.0),
+            material: Material::Subsurface(colour, Vect(1.0, 0.5, 0.1)),
+            surface_map: None,
+        })],
+        vec![],
+        Box::new(Gradient {
+            top: Vect(1.0, 1.0, 1.0),
+            bottom: zero(),
+        }),
+        None,
+    );
+    let settings = RenderSettings {
+        depth: 10,
+        ..RenderSettings::default()
+    };
+    let n = 4000;
+    let mut sampler = sampler::new(settings.sampler, settings.seed, n);
+    let mut stats = RenderStats::default();
+    let mut tot = zero();
+    for i in 0..n {
+        sampler.start_sample(0, 0, i);
+        let path = Ray(Vect(0.0, 1.0, 0.0), Vect(0.0, -1.0, 0.0)).trace(
+            &scene,
+            &settings,
+            &mut *sampler,
+            &mut stats,
+        );
+        tot = tot.add(&path.colour);
+    }
+    let res = tot.scalar_mul(&(6f64 / (5f64 * n as f64)));
+    for (c, expected) in [(res.0, colour.0), (res.1, colour.1), (res.2, colour.2)] {
+        assert!((c - expected).abs() < 0.03, "{} {}", c, expected);
+    }
+}
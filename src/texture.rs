@@ -0,0 +1,159 @@
+//! Images wrapped over surfaces by their uv coordinates, and the normal and
+//! bump maps made from them that show fine detail on a surface without
+//! extra geometry. Both only tilt the normal used for shading; the surface
+//! itself stays where it is.
+
+use crate::typedefs::Intersection;
+use crate::vect::*;
+use std::io::Error;
+
+/// An image repeating every size along u and v, with its top row at v = 0
+pub struct Texture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vect>,
+    size: f64,
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vect>, size: f64) -> Texture {
+        Texture {
+            width,
+            height,
+            pixels,
+            size,
+        }
+    }
+
+    /// Values are read as they are stored, from 0 to 1, without undoing
+    /// any gamma, as normal and height maps hold data rather than colours
+    pub fn load(filename: &str, size: f64) -> Result<Texture, Error> {
+        let img = image::open(filename)
+            .map_err(|e| Error::other(format!("Could not load {}: {}", filename, e)))?
+            .into_rgb32f();
+        let pixels = img
+            .pixels()
+            .map(|p| Vect(p[0] as f64, p[1] as f64, p[2] as f64))
+            .collect();
+        Ok(Texture::new(
+            img.width() as usize,
+            img.height() as usize,
+            pixels,
+            size,
+        ))
+    }
+
+    /// Bilinearly interpolated value at uv
+    pub fn lookup(&self, u: f64, v: f64) -> Vect {
+        // Continuous pixel coordinates, with pixel centres on whole numbers
+        let x = u / self.size * self.width as f64 - 0.5;
+        let y = v / self.size * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let pixel = |i: f64, j: f64| {
+            let col = (i as i64).rem_euclid(self.width as i64) as usize;
+            let row = (j as i64).rem_euclid(self.height as i64) as usize;
+            self.pixels[row * self.width + col]
+        };
+        let lerp = |a: Vect, b: Vect, t: f64| a.scalar_mul(&(1f64 - t)).add(&b.scalar_mul(&t));
+        lerp(
+            lerp(pixel(x0, y0), pixel(x0 + 1f64, y0), tx),
+            lerp(pixel(x0, y0 + 1f64), pixel(x0 + 1f64, y0 + 1f64), tx),
+            ty,
+        )
+    }
+
+    /// Distance along u or v between neighbouring pixels
+    fn pixel_size(&self) -> (f64, f64) {
+        (
+            self.size / self.width as f64,
+            self.size / self.height as f64,
+        )
+    }
+}
+
+pub enum SurfaceMap {
+    /// Tangent space normals stored as colours, x along the tangent in
+    /// red, y up the image in green like OpenGL style maps, and z along the
+    /// normal in blue
+    Normal(Texture),
+    /// Greyscale heights, white being strength above black in scene units,
+    /// so that the same strength gives the same relief on every shape
+    Bump(Texture, f64), //Heights, strength
+}
+
+impl SurfaceMap {
+    /// The normal at the intersection, tilted by the map. It stays on the
+    /// same side of the surface as the one without the map.
+    pub fn shading_normal(&self, intersection: &Intersection) -> Vect {
+        let normal = intersection.normal;
+        let tangent = intersection.tangent;
+        // The direction v increases in, seen from the front of planes
+        let bitangent = normal.cross(&tangent);
+        let (u, v) = intersection.uv;
+        let tilted = match self {
+            SurfaceMap::Normal(texture) => {
+                let Vect(x, y, z) = texture
+                    .lookup(u, v)
+                    .scalar_mul(&2f64)
+                    .sub(&Vect(1.0, 1.0, 1.0));
+                tangent
+                    .scalar_mul(&x)
+                    .sub(&bitangent.scalar_mul(&y))
+                    .add(&normal.scalar_mul(&z))
+            }
+            SurfaceMap::Bump(texture, strength) => {
+                let height = |u: f64, v: f64| texture.lookup(u, v).0 * strength;
+                let (du, dv) = texture.pixel_size();
+                // Rise over the distance travelled along the surface, none
+                // where a step in uv goes nowhere, like at a sphere's poles
+                let slope = |rise: f64, step: f64, scale: f64| {
+                    if scale > 0f64 {
+                        rise / (step * scale)
+                    } else {
+                        0f64
+                    }
+                };
+                let (scale_u, scale_v) = intersection.uv_scale;
+                let slope_u = slope(height(u + du, v) - height(u - du, v), 2f64 * du, scale_u);
+                let slope_v = slope(height(u, v + dv) - height(u, v - dv), 2f64 * dv, scale_v);
+                normal
+                    .sub(&tangent.scalar_mul(&slope_u))
+                    .sub(&bitangent.scalar_mul(&slope_v))
+            }
+        };
+        if tilted.dot(&normal) <= 0f64 {
+            return normal;
+        }
+        tilted.normalise()
+    }
+}
+
+#[test]
+fn surface_map_test() {
+    let intersection = Intersection {
+        pos: zero(),
+        normal: Vect(0.0, 1.0, 0.0),
+        shading_normal: Vect(0.0, 1.0, 0.0),
+        uv: (0.5, 0.5),
+        uv_scale: (1f64, 1f64),
+        tangent: Vect(1.0, 0.0, 0.0),
+    };
+    // A flat normal map leaves the normal as it is
+    let flat = SurfaceMap::Normal(Texture::new(1, 1, vec![Vect(0.5, 0.5, 1.0)], 1f64));
+    let normal = flat.shading_normal(&intersection);
+    assert!(normal.sub(&intersection.normal).norm() < 1e-12);
+    // Heights going up by one along u over one unit tilt the normal back
+    // along u by 45 degrees at a strength of one
+    let ramp = (0..4).map(|x| Vect(x as f64 / 4f64, 0.0, 0.0)).collect();
+    let bump = SurfaceMap::Bump(Texture::new(4, 1, ramp, 1f64), 1f64);
+    let normal = bump.shading_normal(&intersection);
+    assert!(normal.sub(&Vect(-1.0, 1.0, 0.0).normalise()).norm() < 1e-12);
+    // Spread over twice the distance the same heights slope half as much
+    let stretched = Intersection {
+        uv_scale: (2f64, 1f64),
+        ..intersection
+    };
+    let normal = bump.shading_normal(&stretched);
+    assert!(normal.sub(&Vect(-0.5, 1.0, 0.0).normalise()).norm() < 1e-12);
+}
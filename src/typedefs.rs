@@ -1,19 +1,60 @@
+use crate::environment::Environment;
 use crate::geometry::Geometry;
 use crate::light::Light;
+use crate::medium::Medium;
+use crate::principled::Principled;
 use crate::vect::*;
 
 pub struct Intersection {
     pub pos: Vect,
     pub normal: Vect,
+    /// The normal tilted by the surface map, if any, which shading goes by
+    pub shading_normal: Vect,
+    /// Coordinates of the point on the surface, for textures
+    pub uv: (f64, f64),
+    /// Distance along the surface per unit of u and of v there, for bump
+    /// maps. Zero where uv don't mean anything.
+    pub uv_scale: (f64, f64),
+    /// Direction along the surface that u increases in, for normal maps
+    pub tangent: Vect,
+}
+
+impl Intersection {
+    /// No intersection at all, which the zero normal marks
+    pub fn miss() -> Intersection {
+        Intersection {
+            pos: zero(),
+            normal: zero(),
+            shading_normal: zero(),
+            uv: (0f64, 0f64),
+            uv_scale: (0f64, 0f64),
+            tangent: zero(),
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
 pub enum Material {
     Lambertian(Vect), //Albedo
     Mirror,
+    Glass(f64), //Index of refraction
+    /// Lambertian plus white Blinn-Phong highlights. Colour plus specular
+    /// should stay under one for the material not to create light. From a
+    /// shininess of bsdf::MIRROR_SHININESS the highlights are mirror images.
+    Phong(Vect, f64, f64), //Colour, specular, shininess
+    /// No surface at all, only the boundary of the medium inside
+    Interface,
+    /// Light goes into the object and scatters around inside before coming
+    /// back out. Anything but the path tracer sees it as Lambertian.
+    Subsurface(Vect, Vect), //Colour, scattering radius
+    /// Disney style material with artist friendly parameters
+    Principled(Principled),
 }
 
+///               objects, lights, background, fog
 pub type Scene = (
     Vec<Box<dyn Geometry + Send + Sync>>,
     Vec<Box<dyn Light + Send + Sync>>,
+    Box<dyn Environment + Send + Sync>,
+    Option<Medium>,
 );
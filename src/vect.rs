@@ -76,6 +76,12 @@ impl Vect {
         self.norm_sq().sqrt()
     }
 
+    /// Perceived brightness when the vector is an RGB colour
+    pub fn luminance(&self) -> f64 {
+        let Vect(r, g, b) = self;
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+
     /// Produce a normalised vector: same direction, norm == 1.
     pub fn normalise(&self) -> Vect {
         match self.scalar_div(&self.norm()) {
@@ -93,6 +99,7 @@ impl std::fmt::Display for Vect {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn vect_test() {
     //Equality test
     assert_eq!(Vect(0.0, 0.0, 0.0) == Vect(0.0, 0.0, 0.0), true);
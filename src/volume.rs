@@ -0,0 +1,246 @@
+//! Boxes of participating medium whose density varies from place to place,
+//! for smoke, clouds and fire from simulations. The densities come from
+//! dense voxel grids stretched over the box.
+//!
+//! Grid files are little endian: the u32 number of voxels along x, y and z,
+//! then one non-negative f32 per voxel, x changing fastest and z slowest.
+
+use crate::geometry::Geometry;
+use crate::medium::Medium;
+use crate::ray::Ray;
+use crate::typedefs::{Intersection, Material};
+use crate::vect::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, Error};
+
+/// Values at the centres of the voxels of a box from min to max,
+/// interpolated trilinearly in between and zero outside the box
+pub struct VoxelGrid {
+    min: Vect,
+    max: Vect,
+    size: [usize; 3],
+    values: Vec<f32>,
+    /// The largest value, which no interpolated one goes over
+    max_value: f64,
+}
+
+impl VoxelGrid {
+    pub fn new(min: Vect, max: Vect, size: [usize; 3], values: Vec<f32>) -> VoxelGrid {
+        let max_value = values.iter().fold(0f64, |m, v| m.max(*v as f64));
+        VoxelGrid {
+            min,
+            max,
+            size,
+            values,
+            max_value,
+        }
+    }
+
+    pub fn load(filename: &str, min: Vect, max: Vect) -> Result<VoxelGrid, Error> {
+        let mut f = BufReader::new(
+            File::open(filename)
+                .map_err(|e| Error::other(format!("Could not load {}: {}", filename, e)))?,
+        );
+        let mut size = [0usize; 3];
+        for n in size.iter_mut() {
+            let mut bytes = [0u8; 4];
+            f.read_exact(&mut bytes)?;
+            *n = u32::from_le_bytes(bytes) as usize;
+        }
+        let mut bytes = Vec::new();
+        f.read_to_end(&mut bytes)?;
+        let len = size.iter().try_fold(4usize, |len, n| len.checked_mul(*n));
+        if size.contains(&0) || len != Some(bytes.len()) {
+            return Err(Error::other(format!(
+                "{} doesn't hold a {}x{}x{} grid",
+                filename, size[0], size[1], size[2]
+            )));
+        }
+        let values: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        if values.iter().any(|v| !v.is_finite() || *v < 0f32) {
+            return Err(Error::other(format!(
+                "{} holds negative or non-finite values",
+                filename
+            )));
+        }
+        Ok(VoxelGrid::new(min, max, size, values))
+    }
+
+    pub fn max_value(&self) -> f64 {
+        self.max_value
+    }
+
+    pub fn lookup(&self, pos: &Vect) -> f64 {
+        let [nx, ny, nz] = self.size;
+        // Continuous voxel coordinates, with voxel centres on whole numbers
+        let mut coords = [0f64; 3];
+        for (axis, (p, lo, hi, n)) in [
+            (pos.0, self.min.0, self.max.0, nx),
+            (pos.1, self.min.1, self.max.1, ny),
+            (pos.2, self.min.2, self.max.2, nz),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let t = (p - lo) / (hi - lo);
+            if !(0f64..=1f64).contains(&t) {
+                return 0f64;
+            }
+            coords[axis] = (t * n as f64 - 0.5).clamp(0f64, (n - 1) as f64);
+        }
+        let [x, y, z] = coords.map(|c| (c.floor() as usize, c.fract()));
+        let value = |i: usize, j: usize, k: usize| {
+            self.values[(k.min(nz - 1) * ny + j.min(ny - 1)) * nx + i.min(nx - 1)] as f64
+        };
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let plane = |k: usize| {
+            lerp(
+                lerp(value(x.0, y.0, k), value(x.0 + 1, y.0, k), x.1),
+                lerp(value(x.0, y.0 + 1, k), value(x.0 + 1, y.0 + 1, k), x.1),
+                y.1,
+            )
+        };
+        lerp(plane(z.0), plane(z.0 + 1), z.1)
+    }
+}
+
+/// An axis aligned box from min to max with no surface, filled with medium
+pub struct Volume {
+    pub min: Vect,
+    pub max: Vect,
+    pub medium: Medium,
+}
+
+impl Geometry for Volume {
+    fn intersect(&self, ray: &Ray) -> Intersection {
+        let Ray(rpos, rdir) = ray;
+        let miss = Intersection::miss();
+        // Where the ray enters and leaves the slab between the box's faces
+        // along each axis, and the normal of the face it enters through
+        let mut t_near = f64::NEG_INFINITY;
+        let mut t_far = f64::INFINITY;
+        let mut near_normal = zero();
+        let mut far_normal = zero();
+        for (axis, (p, d, lo, hi)) in [
+            (rpos.0, rdir.0, self.min.0, self.max.0),
+            (rpos.1, rdir.1, self.min.1, self.max.1),
+            (rpos.2, rdir.2, self.min.2, self.max.2),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut normal = [0f64; 3];
+            normal[axis] = -1f64;
+            if d == 0f64 {
+                if p < lo || p > hi {
+                    return miss;
+                }
+                continue;
+            }
+            let (mut t0, mut t1) = ((lo - p) / d, (hi - p) / d);
+            if t0 > t1 {
+                (t0, t1) = (t1, t0);
+                normal[axis] = 1f64;
+            }
+            let normal = Vect(normal[0], normal[1], normal[2]);
+            if t0 > t_near {
+                t_near = t0;
+                near_normal = normal;
+            }
+            if t1 < t_far {
+                t_far = t1;
+                far_normal = normal.scalar_mul(&-1f64);
+            }
+        }
+        if t_near > t_far || t_far < 0f64 {
+            return miss;
+        }
+        // From inside the box the ray only hits the face it leaves through
+        let (t, normal) = if t_near >= 0f64 {
+            (t_near, near_normal)
+        } else {
+            (t_far, far_normal)
+        };
+        Intersection {
+            pos: rpos.add(&rdir.scalar_mul(&t)),
+            normal,
+            shading_normal: normal,
+            uv: (0f64, 0f64),
+            uv_scale: (0f64, 0f64),
+            tangent: zero(),
+        }
+    }
+
+    fn get_material(&self) -> Material {
+        Material::Interface
+    }
+
+    fn get_medium(&self) -> Option<&Medium> {
+        Some(&self.medium)
+    }
+}
+
+#[test]
+fn voxel_grid_test() {
+    // A 2x2x2 grid over the unit cube, with one voxel set
+    let mut values = vec![0f32; 8];
+    values[7] = 8.0;
+    let grid = VoxelGrid::new(zero(), Vect(1.0, 1.0, 1.0), [2, 2, 2], values);
+    assert_eq!(grid.max_value(), 8f64);
+    // Full value from the voxel's centre to the corner of the box
+    assert_eq!(grid.lookup(&Vect(0.75, 0.75, 0.75)), 8f64);
+    assert_eq!(grid.lookup(&Vect(0.9, 1.0, 0.8)), 8f64);
+    // Interpolated from there to the opposite voxel's centre
+    assert!((grid.lookup(&Vect(0.5, 0.5, 0.5)) - 1f64).abs() < 1e-12);
+    assert!((grid.lookup(&Vect(0.75, 0.75, 0.5)) - 4f64).abs() < 1e-12);
+    assert_eq!(grid.lookup(&Vect(1.1, 0.75, 0.75)), 0f64);
+}
+
+#[test]
+fn voxel_grid_load_test() {
+    // Sizes whose byte count overflows and values no density can have are
+    // turned down rather than loaded
+    let filename = std::env::temp_dir().join("rtracer_voxel_grid_load_test.vol");
+    let filename = filename.to_str().unwrap();
+    let load = |size: [u32; 3], values: &[f32]| {
+        let mut bytes: Vec<u8> = size.iter().flat_map(|n| n.to_le_bytes()).collect();
+        bytes.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+        std::fs::write(filename, bytes).unwrap();
+        VoxelGrid::load(filename, zero(), Vect(1.0, 1.0, 1.0))
+    };
+    assert!(load([1, 1, 2], &[0.5, 2.0]).is_ok());
+    assert!(load([1, 1, 2], &[0.5]).is_err());
+    assert!(load([u32::MAX, u32::MAX, u32::MAX], &[0.5]).is_err());
+    assert!(load([1, 1, 2], &[0.5, f32::NAN]).is_err());
+    assert!(load([1, 1, 2], &[0.5, f32::INFINITY]).is_err());
+    assert!(load([1, 1, 2], &[-0.5, 2.0]).is_err());
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[test]
+fn volume_intersection_test() {
+    let volume = Volume {
+        min: Vect(-1.0, -1.0, 4.0),
+        max: Vect(1.0, 1.0, 6.0),
+        medium: Medium {
+            sigma_a: 0.0,
+            sigma_s: 1.0,
+            g: 0.0,
+            density: None,
+            emission: zero(),
+            emission_grid: None,
+        },
+    };
+    let outside = volume.intersect(&Ray(zero(), Vect(0.0, 0.0, 1.0)));
+    assert_eq!(outside.pos, Vect(0.0, 0.0, 4.0));
+    assert_eq!(outside.normal, Vect(0.0, 0.0, -1.0));
+    let inside = volume.intersect(&Ray(Vect(0.0, 0.0, 5.0), Vect(0.0, 1.0, 0.0)));
+    assert_eq!(inside.pos, Vect(0.0, 1.0, 5.0));
+    assert_eq!(inside.normal, Vect(0.0, 1.0, 0.0));
+    let past = volume.intersect(&Ray(Vect(0.0, 0.0, 7.0), Vect(0.0, 0.0, 1.0)));
+    assert_eq!(past.normal, zero());
+}